@@ -82,6 +82,7 @@ func (b *BinaryInterpreter) RunSourceWithPrinter(absPath, source string, p IPrin
 	cmd := osexec.CommandContext(ctx, b.path, f.Name())
 	cmd.Env = os.Environ()
 	cmd.Env = append(cmd.Env, "GROTSKY_SKIP_BACKTRACE=1")
+	cmd.Env = append(cmd.Env, "GROTSKY_PLAIN_ERRORS=1")
 	cwd, _ := os.Getwd()
 	profilePath := fmt.Sprintf("LLVM_PROFILE_FILE=%s/../grotsky-%%p-%%m.profraw", cwd)
 	cmd.Env = append(cmd.Env, profilePath)
@@ -90,7 +91,10 @@ func (b *BinaryInterpreter) RunSourceWithPrinter(absPath, source string, p IPrin
 	cmd.Stderr = b
 	cmd.Dir = "../"
 	if err := cmd.Run(); err != nil {
-		panic(err)
+		// Failing scripts exit with a non-zero code, their output is still checked
+		if _, ok := err.(*osexec.ExitError); !ok {
+			panic(err)
+		}
 	}
 	out_str := string(b.buffer)
 	p.Println(out_str)
@@ -295,6 +299,11 @@ func TestParser(t *testing.T) {
 		checkLexer(t, `"asd" = 3`, 1, errUndefinedStmt.Error())
 	}
 
+	// Conditional expression error
+	{
+		checkLexer(t, `let a = true ? 1`, 1, "Expected ':' after then branch of conditional expression")
+	}
+
 	// Consume error
 	{
 		checkLexer(t, "class A{} let i = 2", 1, errExpectedNewline.Error())
@@ -408,6 +417,37 @@ func TestExpressions(t *testing.T) {
 		checkExpression(t, "true or false", "true")
 	}
 
+	// Conditional expression
+	{
+		checkExpression(t, `true ? 1 : 2`, `1`)
+		checkExpression(t, `nil ? 1 : 2`, `2`)
+		checkExpression(t, `0 ? "a" : "b"`, `b`)
+		checkExpression(t, `1 > 2 ? "a" : 1 < 2 ? "b" : "c"`, `b`)
+		checkStatements(t, `
+		let log = []
+		fn t(x) {
+			log = log + [x]
+			return x
+		}
+		let a = true ? t(1) : t(2)
+		let b = false ? t(3) : t(4)
+		`, "[a, b, log]", "[1, 4, [1, 4]]")
+		checkStatements(t, `
+		fn sign(n) {
+			return n > 0 ? "+" : n < 0 ? "-" : "0"
+		}
+		`, "[sign(5), sign(-2), sign(0)]", `["+", "-", "0"]`)
+		checkStatements(t, `
+		fn run(flag) {
+			let local = 0
+			local = flag ? 1 : 2
+			return local
+		}
+		let global = nil
+		global = run(false) == 2 ? "yes" : "no"
+		`, "[run(true), global]", `[1, "yes"]`)
+	}
+
 	// Strings
 	{
 		// String literal
@@ -433,6 +473,16 @@ func TestExpressions(t *testing.T) {
 		checkExpression(t, `"test"[0:2]`, `te`)
 		checkExpression(t, `"longtest"[1:6:2]`, `oge`)
 		checkExpression(t, `""[1:6:2]`, ``)
+
+		// String interpolation
+		checkExpression(t, `f"{1 + 2} {"a"} {nil} {{}} {f"in {[1, "b"]}"}"`, `3 a <nil> {} in [1, "b"]`)
+		checkStatements(t, `
+		let name = "ana"
+		fn wrap(x) {
+			return f"<{x}>"
+		}
+		let s = f"hi {name}, {wrap(name)}"
+		`, "s", "hi ana, <ana>")
 	}
 
 	// Comparisons
@@ -597,6 +647,10 @@ func TestRuntimeErrors(t *testing.T) {
 		// Wrong slicing type
 		checkErrorMsg(t, `[1,2,3,4,5,6]["0":]`, fmt.Sprintf("%s: [", errOnlyNumbers.Error()), 1)
 
+		// Wrong index type
+		checkErrorMsg(t, `[1,2,3,4,5,6]["0"]`, fmt.Sprintf("%s: [", errOnlyNumbers.Error()), 1)
+		checkErrorMsg(t, `"abc"["0"]`, fmt.Sprintf("%s: [", errOnlyNumbers.Error()), 1)
+
 		// Cannot slice a number
 		checkErrorMsg(t, `2[0]`, fmt.Sprintf("%s: [", errInvalidAccess.Error()), 1)
 
@@ -606,6 +660,14 @@ func TestRuntimeErrors(t *testing.T) {
 		// Set prop list
 		checkErrorMsg(t, `[].prop = 1`, fmt.Sprintf("%s: prop", errReadOnly.Error()), 1)
 
+		// Set index on non-collection
+		checkErrorMsg(t, `let a = "abc"
+		a[0] = 1`, fmt.Sprintf("%s: [", errInvalidAccess.Error()), 2)
+
+		// Set list with non-number index
+		checkErrorMsg(t, `let a = [1]
+		a["0"] = 1`, fmt.Sprintf("%s: [", errOnlyNumbers.Error()), 2)
+
 		// Operate on list + non-list
 		checkErrorMsg(t, `[] + ""`, fmt.Sprintf("%s: +", errExpectedList.Error()), 1)
 
@@ -629,6 +691,14 @@ func TestRuntimeErrors(t *testing.T) {
 		checkErrorMsg(t, `nil <= nil`, fmt.Sprintf("%s: <=", errUndefinedOp.Error()), 1)
 		checkErrorMsg(t, `nil + ""`, fmt.Sprintf("%s: +", errUndefinedOp.Error()), 1)
 		checkErrorMsg(t, `[] + nil`, fmt.Sprintf("%s: +", errUndefinedOp.Error()), 1)
+
+		// Undefined arithmetic and comparison between number and string
+		checkErrorMsg(t, `1 / "a"`, fmt.Sprintf("%s: /", errUndefinedOp.Error()), 1)
+		checkErrorMsg(t, `1 % "a"`, fmt.Sprintf("%s: %%", errUndefinedOp.Error()), 1)
+		checkErrorMsg(t, `1 ^ "a"`, fmt.Sprintf("%s: ^", errUndefinedOp.Error()), 1)
+		checkErrorMsg(t, `1 < "a"`, fmt.Sprintf("%s: <", errUndefinedOp.Error()), 1)
+		checkErrorMsg(t, `1 > "a"`, fmt.Sprintf("%s: >", errUndefinedOp.Error()), 1)
+		checkErrorMsg(t, `1 >= "a"`, fmt.Sprintf("%s: >=", errUndefinedOp.Error()), 1)
 	}
 
 	// Statement errors
@@ -651,6 +721,10 @@ func TestRuntimeErrors(t *testing.T) {
 		// Undefined variable assignment
 		checkErrorMsg(t, `a = 1`, fmt.Sprintf("%s: a", errUndefinedVar.Error()), 1)
 
+		// This outside a method
+		checkErrorMsg(t, `fn f() { return this }
+		f()`, fmt.Sprintf("%s: this", errUndefinedVar.Error()), 1)
+
 		// Access on booleans
 		checkErrorMsg(t, `
 		let a = true
@@ -775,6 +849,25 @@ func TestRuntimeErrors(t *testing.T) {
 		A() + A()
 		`, fmt.Sprintf("%s: +", errUndefinedOperator.Error()), 4)
 	}
+
+	// Uncaught throw
+	{
+		checkErrorMsg(t, `throw "boom"`, "Uncaught boom", 1)
+
+		checkErrorMsg(t, `
+		class Fail {
+			init() {
+				this.message = "bad"
+			}
+		}
+		throw Fail()
+		`, "Uncaught Fail: bad", 7)
+	}
+
+	// Compound assignment
+	{
+		checkErrorMsg(t, "let a = \"a\"\na -= 1", fmt.Sprintf("%s: -=", errUndefinedOp.Error()), 2)
+	}
 }
 
 func TestGlobals(t *testing.T) {
@@ -989,6 +1082,26 @@ func TestStatements(t *testing.T) {
 		let f = testBreakContinue()
 		`, "f", "11")
 
+		checkStatements(t, `
+		let sum = 0
+		for e in [1, 2, 3, 4] {
+			if e == 2 {
+				continue
+			}
+			sum = sum + e
+		}
+		`, "sum", "8")
+
+		checkStatements(t, `
+		let sum = 0
+		for let i = 0; i < 5; i = i + 1 {
+			if i % 2 == 0 {
+				continue
+			}
+			sum = sum + i
+		}
+		`, "sum", "4")
+
 		checkStatements(t, `
 		fn firstEl(arr) {
 			for e in arr {
@@ -1168,6 +1281,170 @@ func TestStatements(t *testing.T) {
 		a.do()`, `a.dict["a"]`, "1")
 	}
 
+	// Throw
+	{
+		checkStatements(t, `
+		class Fail {
+			init(code) {
+				this.code = code
+			}
+		}
+		let code = nil
+		try {
+			throw Fail(3)
+		} catch err {
+			code = err.code
+		}
+		`, "code", "3")
+
+		checkStatements(t, `
+		let caught = nil
+		try {
+			throw "boom"
+		} catch err {
+			caught = err
+		}
+		`, "caught", "boom")
+
+		// From a nested call
+		checkStatements(t, `
+		fn fail() {
+			throw [1, 2]
+		}
+		let caught = nil
+		try {
+			fail()
+		} catch err {
+			caught = err
+		}
+		`, "caught", "[1, 2]")
+	}
+
+	// Finally
+	{
+		// Runs on return
+		checkStatements(t, `
+		let log = []
+		fn run() {
+			try {
+				return "return"
+			} finally {
+				log = log + ["finally"]
+			}
+		}
+		let result = run()
+		log = log + [result]
+		`, "log", `["finally", "return"]`)
+
+		// Runs on break
+		checkStatements(t, `
+		let log = []
+		while true {
+			try {
+				break
+			} finally {
+				log = log + ["finally"]
+			}
+		}
+		`, "log", `["finally"]`)
+
+		// Runs on continue
+		checkStatements(t, `
+		let log = []
+		for let i = 0; i < 2; i = i + 1 {
+			try {
+				continue
+			} finally {
+				log = log + [i]
+			}
+		}
+		`, "log", `[0, 1]`)
+
+		// Runs after the try body
+		checkStatements(t, `
+		let log = []
+		try {
+			log = log + ["try"]
+		} catch err {
+			log = log + ["catch"]
+		} finally {
+			log = log + ["finally"]
+		}
+		`, "log", `["try", "finally"]`)
+
+		// Rethrow from catch
+		checkStatements(t, `
+		let log = []
+		fn run() {
+			try {
+				throw "error"
+			} catch err {
+				log = log + [err]
+				throw err
+			} finally {
+				log = log + ["finally"]
+			}
+		}
+		try {
+			run()
+		} catch err {
+			log = log + [err]
+		}
+		`, "log", `["error", "finally", "error"]`)
+	}
+
+	// Compound assignment
+	{
+		// Variables, upvalues and globals
+		checkStatements(t, `
+		let total = 10
+		total -= 4
+		fn run() {
+			let local = 2
+			fn bump() {
+				local ^= 3
+			}
+			bump()
+			local -= 1
+			total %= 4
+			return local
+		}
+		let local = run()
+		`, `[local, total]`, `[7, 2]`)
+
+		// Subscripts
+		checkStatements(t, `
+		let list = [1, [2, 3]]
+		list[1][0] *= 5
+		list[0] += 1
+		let dict = {"a": 1}
+		dict["a"] /= 4
+		`, `[list, dict["a"]]`, `[[2, [10, 3]], 0.25]`)
+
+		// Properties, the target is evaluated once
+		checkStatements(t, `
+		let calls = 0
+		class Counter {
+			init() {
+				this.n = 1
+			}
+		}
+		let counter = Counter()
+		fn target() {
+			calls += 1
+			return counter
+		}
+		fn index() {
+			calls += 1
+			return 0
+		}
+		let list = [5]
+		target().n += 2
+		target().n *= 4
+		list[index()] -= 5
+		`, `[counter.n, list[0], calls]`, `[12, 0, 3]`)
+	}
+
 	// Strings module
 	{
 		checkStatements(t, `
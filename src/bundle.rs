@@ -0,0 +1,147 @@
+use std::fs::canonicalize;
+use std::path::{Component, Path, PathBuf};
+
+use crate::lexer::Lexer;
+use crate::state::InterpreterState;
+use crate::token::{Literal, Token};
+
+/// Paths of the modules imported with a string literal, like `import("lib.gr")`.
+pub fn find_imports(source: &str) -> Vec<String> {
+    let state = &mut InterpreterState::new(String::new(), source.to_string());
+    let mut lexer = Lexer::new(state);
+    lexer.scan();
+    let tokens = &state.tokens;
+    let mut imports = vec![];
+    for (ix, window) in tokens.windows(4).enumerate() {
+        // Skip properties named import, as in obj.import("x")
+        if ix > 0 && tokens[ix - 1].token == Token::Dot {
+            continue;
+        }
+        match (&window[0], &window[1], &window[2], &window[3]) {
+            (name, open, path, close)
+                if name.token == Token::Identifier
+                    && name.lexeme == "import"
+                    && open.token == Token::LeftParen
+                    && path.token == Token::String
+                    && close.token == Token::RightParen =>
+            {
+                if let Some(Literal::String(path)) = &path.literal {
+                    imports.push(path.clone());
+                }
+            }
+            _ => (),
+        }
+    }
+    imports
+}
+
+/// Path of a module imported from the file at `file_path`. Relative paths start
+/// at the directory of that file, which doesn't need to exist.
+pub fn resolve(file_path: &str, module_path: &str) -> PathBuf {
+    let module_path = Path::new(module_path);
+    if module_path.is_absolute() {
+        return normalize(module_path);
+    }
+    let file = canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+    let dir = file.parent().unwrap_or(Path::new(""));
+    normalize(&dir.join(module_path))
+}
+
+/// Directory the bundled modules are relative to, the one holding the main script.
+pub fn root(main_file_path: &str) -> PathBuf {
+    resolve(main_file_path, ".")
+}
+
+/// Name a module is bundled under, its path relative to `root` with '/' separators.
+pub fn module_key(root: &Path, path: &Path) -> Option<String> {
+    let root: Vec<Component> = root.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = root
+        .iter()
+        .zip(path.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    // Paths on different windows drives can't be related
+    if common == 0 && !root.is_empty() {
+        return None;
+    }
+    let mut parts: Vec<String> = vec!["..".to_string(); root.len() - common];
+    for component in &path[common..] {
+        parts.push(component.as_os_str().to_string_lossy().to_string());
+    }
+    Some(parts.join("/"))
+}
+
+// Resolves '.' and '..' without touching the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode;
+    use crate::interpreter::Runtime;
+    use crate::value::Value;
+    use std::{env, fs};
+
+    #[test]
+    fn test_find_imports() {
+        let source = r#"let a = import("a.gr")
+let b = import("lib/b.gr").b
+obj.import("c.gr")
+let path = "d.gr"
+import(path)"#;
+        assert_eq!(find_imports(source), vec!["a.gr", "lib/b.gr"]);
+    }
+
+    #[test]
+    fn test_module_key() {
+        let root = root("/app/main.grc");
+        assert_eq!(root, PathBuf::from("/app"));
+        let key = |file: &str, module: &str| module_key(&root, &resolve(file, module));
+        assert_eq!(key("/app/main.grc", "lib/a.gr"), Some("lib/a.gr".to_string()));
+        assert_eq!(key("/app/lib/a.gr", "./b.gr"), Some("lib/b.gr".to_string()));
+        assert_eq!(key("/app/lib/a.gr", "../c.gr"), Some("c.gr".to_string()));
+        assert_eq!(key("/app/main.grc", "../shared/d.gr"), Some("../shared/d.gr".to_string()));
+        assert_eq!(key("/app/main.grc", "/app/e.gr"), Some("e.gr".to_string()));
+    }
+
+    #[test]
+    fn test_bundled_modules() {
+        let dir = env::temp_dir().join("grotsky_test_bundle");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/a.gr"), "let b = import(\"b.gr\")\nlet value = b.value * 2").unwrap();
+        fs::write(dir.join("lib/b.gr"), "let value = 21").unwrap();
+        let main = dir.join("main.gr").to_string_lossy().to_string();
+        let mut runtime = Runtime::new(main.clone());
+        let source = "let a = import(\"lib/a.gr\")\nreturn a.value";
+        let bytecode = runtime.compile_to_bytecode(source.to_string()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        for strip in [false, true] {
+            let options = bytecode::Options {
+                strip,
+                compress: false,
+            };
+            let bytecode = bytecode::encode(&bytecode::decode(&bytecode).unwrap(), &options);
+            let mut runtime = Runtime::new(main.clone());
+            match runtime.run_interpreter_from_bytecode(&bytecode) {
+                Ok(Value::Number(n)) => assert_eq!(n.n, 42.0),
+                other => panic!("Expected a number, got {:?}", other),
+            }
+        }
+    }
+}
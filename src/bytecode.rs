@@ -0,0 +1,378 @@
+use std::path::Path;
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec;
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{Compiler, FnContext, InstSrc};
+use crate::errors::Error;
+use crate::token::{Token, TokenData};
+
+// Layout of a .grc file, integers are little endian:
+//
+//   magic           4 bytes
+//   format version  u16
+//   grotsky version u8 length followed by the version string
+//   flags           u8, FLAG_*
+//   checksum        u64, FNV-1a of the payload as stored
+//   payload         bincode serialized Compiler, or Stripped when FLAG_STRIPPED is set.
+//                   Deflated when FLAG_COMPRESSED is set
+pub const MAGIC: &[u8; 4] = b"\0GRC";
+pub const FORMAT_VERSION: u16 = 8;
+pub const GROTSKY_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const FLAG_COMPRESSED: u8 = 1;
+pub const FLAG_STRIPPED: u8 = 2;
+
+const COMPRESSION_LEVEL: u8 = 6;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Drop source tokens, keeping only the line of each instruction
+    pub strip: bool,
+    /// Deflate the payload
+    pub compress: bool,
+}
+
+// Starting instruction and source line of consecutive instructions on the same line.
+// Line 0 marks instructions without source information.
+type LineTable = Vec<(u32, i32)>;
+
+#[derive(Serialize, Deserialize)]
+struct Stripped {
+    compiler: Compiler,
+    main_lines: LineTable,
+    module_lines: Vec<LineTable>,
+    prototype_lines: Vec<LineTable>,
+}
+
+/// Whether the data starts like a .grc file. Anything else is treated as source code.
+pub fn is_bytecode(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn encode(compiler: &Compiler, options: &Options) -> Vec<u8> {
+    let mut flags = 0;
+    let mut payload = if options.strip {
+        flags |= FLAG_STRIPPED;
+        bincode::serialize(&strip(compiler)).unwrap()
+    } else {
+        bincode::serialize(compiler).unwrap()
+    };
+    if options.compress {
+        flags |= FLAG_COMPRESSED;
+        payload = compress_to_vec(&payload, COMPRESSION_LEVEL);
+    }
+    let mut data = Vec::with_capacity(payload.len() + 32);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    data.push(GROTSKY_VERSION.len() as u8);
+    data.extend_from_slice(GROTSKY_VERSION.as_bytes());
+    data.push(flags);
+    data.extend_from_slice(&checksum(&payload).to_le_bytes());
+    data.extend_from_slice(&payload);
+    data
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub format_version: u16,
+    pub grotsky_version: String,
+    pub flags: u8,
+    pub checksum: u64,
+}
+
+/// Reads the header of a .grc file without checking that this version can run it.
+/// Returns the header and the payload that follows it.
+pub fn read_header(data: &[u8]) -> Result<(Header, &[u8]), Error> {
+    if !is_bytecode(data) {
+        return Err(Error::InvalidBytecode);
+    }
+    let mut reader = Reader {
+        data,
+        pos: MAGIC.len(),
+    };
+    let format_version = u16::from_le_bytes(reader.take_array()?);
+    // Older formats may not have the rest of the fields
+    if format_version != FORMAT_VERSION {
+        let header = Header {
+            format_version,
+            grotsky_version: String::new(),
+            flags: 0,
+            checksum: 0,
+        };
+        return Ok((header, reader.rest()));
+    }
+    let version_len = reader.take(1)?[0] as usize;
+    let grotsky_version = String::from_utf8_lossy(reader.take(version_len)?).to_string();
+    let flags = reader.take(1)?[0];
+    let checksum = u64::from_le_bytes(reader.take_array()?);
+    let header = Header {
+        format_version,
+        grotsky_version,
+        flags,
+        checksum,
+    };
+    Ok((header, reader.rest()))
+}
+
+/// Fails with `Error::InvalidBytecode` when the data is not a .grc file, and with
+/// `Error::Bytecode` when it was built by another version or is corrupted.
+pub fn decode(data: &[u8]) -> Result<Compiler, Error> {
+    let (header, payload) = read_header(data)?;
+    if header.format_version != FORMAT_VERSION {
+        return Err(Error::Bytecode(format!(
+            "Unsupported bytecode format version {}, expected {}",
+            header.format_version, FORMAT_VERSION
+        )));
+    }
+    if header.grotsky_version != GROTSKY_VERSION {
+        return Err(Error::Bytecode(format!(
+            "Bytecode was compiled by grotsky {}, this is grotsky {}. Compile the script again",
+            header.grotsky_version, GROTSKY_VERSION
+        )));
+    }
+    let flags = header.flags;
+    let mut payload = payload.to_vec();
+    if checksum(&payload) != header.checksum {
+        return Err(Error::Bytecode(
+            "Bytecode checksum mismatch, the file is corrupted".to_string(),
+        ));
+    }
+    let invalid = |err: String| Error::Bytecode(format!("Cannot decode bytecode: {}", err));
+    if flags & FLAG_COMPRESSED != 0 {
+        payload = decompress_to_vec(&payload).map_err(|err| invalid(err.to_string()))?;
+    }
+    if flags & FLAG_STRIPPED != 0 {
+        let stripped = bincode::deserialize::<Stripped>(&payload)
+            .map_err(|err| invalid(err.to_string()))?;
+        Ok(unstrip(stripped))
+    } else {
+        bincode::deserialize::<Compiler>(&payload).map_err(|err| invalid(err.to_string()))
+    }
+}
+
+// Removes everything that isn't needed to run the code: source tokens, names of
+// locals and the directories of the source files. Modules keep their top level
+// locals, those are the names they export.
+fn strip(compiler: &Compiler) -> Stripped {
+    let mut compiler = compiler.clone();
+    let mut main_lines = vec![];
+    if let Some(context) = compiler.contexts.last_mut() {
+        main_lines = strip_context(context);
+        for block in context.blocks.iter_mut() {
+            block.locals.clear();
+        }
+    }
+    let module_lines = compiler.modules.values_mut().map(strip_context).collect();
+    let mut prototype_lines = vec![];
+    for prototype in compiler.prototypes.iter_mut() {
+        prototype_lines.push(line_table(prototype.instruction_data.iter()));
+        prototype.instruction_data.clear();
+        prototype.file_path = file_name(&prototype.file_path);
+    }
+    Stripped {
+        compiler,
+        main_lines,
+        module_lines,
+        prototype_lines,
+    }
+}
+
+fn strip_context(context: &mut FnContext) -> LineTable {
+    let mut instructions: Vec<&mut InstSrc> = context
+        .chunks
+        .iter_mut()
+        .flat_map(|chunk| chunk.instructions.iter_mut())
+        .collect();
+    let table = line_table(instructions.iter().map(|i| &i.src));
+    for inst in instructions.iter_mut() {
+        inst.src = None;
+    }
+    table
+}
+
+fn unstrip_context(context: &mut FnContext, table: &LineTable) {
+    let instructions: Vec<&mut InstSrc> = context
+        .chunks
+        .iter_mut()
+        .flat_map(|chunk| chunk.instructions.iter_mut())
+        .collect();
+    let lines = lines(table, instructions.len());
+    for (inst, src) in instructions.into_iter().zip(lines) {
+        inst.src = src;
+    }
+}
+
+fn unstrip(stripped: Stripped) -> Compiler {
+    let mut compiler = stripped.compiler;
+    if let Some(context) = compiler.contexts.last_mut() {
+        unstrip_context(context, &stripped.main_lines);
+    }
+    for (context, table) in compiler.modules.values_mut().zip(&stripped.module_lines) {
+        unstrip_context(context, table);
+    }
+    for (prototype, table) in compiler.prototypes.iter_mut().zip(&stripped.prototype_lines) {
+        prototype.instruction_data = lines(table, prototype.instructions.len());
+    }
+    compiler
+}
+
+fn line_table<'a>(data: impl Iterator<Item = &'a Option<TokenData>>) -> LineTable {
+    let mut table: LineTable = vec![];
+    for (pc, src) in data.enumerate() {
+        let line = src.as_ref().map_or(0, |tk| tk.line);
+        if table.last().is_none_or(|(_, last)| *last != line) {
+            table.push((pc as u32, line));
+        }
+    }
+    table
+}
+
+// Source information rebuilt from a line table, tokens only carry the line
+fn lines(table: &LineTable, len: usize) -> Vec<Option<TokenData>> {
+    let mut data = Vec::with_capacity(len);
+    for (ix, (start, line)) in table.iter().enumerate() {
+        let end = table.get(ix + 1).map_or(len, |(next, _)| *next as usize);
+        for _ in (*start as usize)..end.min(len) {
+            data.push(if *line == 0 {
+                None
+            } else {
+                Some(TokenData {
+                    token: Token::Nil,
+                    lexeme: String::new(),
+                    literal: None,
+                    line: *line,
+                    column: 0,
+                })
+            });
+        }
+    }
+    data.resize(len, None);
+    data
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().to_string())
+}
+
+/// FNV-1a hash, used to detect corrupted files.
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos + len > self.data.len() {
+            return Err(Error::Bytecode("Truncated bytecode header".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Runtime;
+
+    fn message(result: Result<Compiler, Error>) -> String {
+        match result {
+            Err(Error::Bytecode(msg)) => msg,
+            _ => panic!("expected a bytecode error"),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut compiler = Compiler::new("test.gr".to_string());
+        compiler.globals.insert("a".to_string());
+        let data = encode(&compiler, &Options::default());
+        assert!(is_bytecode(&data));
+        assert!(decode(&data).unwrap().globals.contains("a"));
+
+        assert!(matches!(decode(b"let a = 1"), Err(Error::InvalidBytecode)));
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(message(decode(&corrupted)).contains("checksum"));
+
+        let mut other_format = data.clone();
+        other_format[4] = 99;
+        assert!(message(decode(&other_format)).contains("format version 99"));
+
+        let mut other_version = data.clone();
+        other_version[7] = b'x';
+        assert!(message(decode(&other_version)).contains("compiled by grotsky"));
+
+        assert!(message(decode(&data[..8])).contains("Truncated"));
+    }
+
+    #[test]
+    fn test_strip_and_compress() {
+        let mut runtime = Runtime::new("/tmp/dir/test.gr".to_string());
+        let source = "let a = 1\nfn f(x) {\n    let y = x\n\n    return y + a\n}\nf(2)";
+        let compiler = runtime.compile(source.to_string()).unwrap().clone();
+        let full = encode(&compiler, &Options::default());
+        for compress in [false, true] {
+            let options = Options {
+                strip: true,
+                compress,
+            };
+            let data = encode(&compiler, &options);
+            assert!(data.len() < full.len());
+            let decoded = decode(&data).unwrap();
+            let prototype = &decoded.prototypes[0];
+            assert_eq!(prototype.file_path, "test.gr");
+            assert_eq!(prototype.instruction_data.len(), prototype.instructions.len());
+            let lines: Vec<i32> = prototype
+                .instruction_data
+                .iter()
+                .zip(&compiler.prototypes[0].instruction_data)
+                .map(|(stripped, src)| {
+                    assert!(stripped.as_ref().is_none_or(|tk| tk.lexeme.is_empty()));
+                    assert_eq!(stripped.is_some(), src.is_some());
+                    stripped.as_ref().map_or(0, |tk| tk.line)
+                })
+                .collect();
+            let original: Vec<i32> = compiler.prototypes[0]
+                .instruction_data
+                .iter()
+                .map(|src| src.as_ref().map_or(0, |tk| tk.line))
+                .collect();
+            assert_eq!(lines, original);
+            assert!(decoded.contexts[0].blocks.iter().all(|b| b.locals.is_empty()));
+        }
+
+        let compressed = Options {
+            strip: false,
+            compress: true,
+        };
+        let decoded = decode(&encode(&compiler, &compressed)).unwrap();
+        assert_eq!(
+            decoded.prototypes[0].instruction_data,
+            compiler.prototypes[0].instruction_data
+        );
+    }
+}
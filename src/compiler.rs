@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
 
-use crate::errors::RuntimeErr;
+use crate::errors::{source_line, CompileErr, Location, RuntimeErr};
 use crate::errors::{ERR_GLOBAL_ALREADY_DEFINED, ERR_UNDEFINED_VAR};
 use crate::expr::*;
 use crate::instruction::*;
@@ -15,15 +16,43 @@ pub struct Compiler {
     pub contexts: Vec<FnContext>,
     pub prototypes: Vec<FnPrototype>,
     pub globals: HashSet<String>,
+    // Top level code of the modules imported by the script, by path relative to it.
+    // Their functions and constants are stored along with the ones of the script
+    pub modules: BTreeMap<String, FnContext>,
+    // Source file being compiled, recorded on every function prototype
+    #[serde(skip)]
+    pub file_path: String,
+    // Source code being compiled, errors show lines of it. Bytecode comes without it
+    #[serde(skip)]
+    pub source: Option<Rc<str>>,
+    #[serde(skip)]
+    pub errors: Vec<CompileErr>,
+    // Names provided by the runtime, resolved with GetBuiltin
+    #[serde(skip)]
+    pub builtins: HashSet<String>,
 }
 
 impl Compiler {
+    pub fn new(file_path: String) -> Compiler {
+        Compiler {
+            constants: vec![],
+            contexts: vec![],
+            prototypes: vec![],
+            globals: HashSet::new(),
+            modules: BTreeMap::new(),
+            file_path,
+            source: None,
+            errors: vec![],
+            builtins: HashSet::new(),
+        }
+    }
+
     fn add_chunk(&mut self, chunk: Chunk) {
         let current_context = self.contexts.last_mut().unwrap();
         current_context.chunks.push(chunk);
     }
 
-    fn allocate_register(&mut self, var_name: String, reg: u8) {
+    fn allocate_register(&mut self, var_name: String, reg: u32) {
         // TODO: handle what to do here when we're in the global context
         let current_context = self.contexts.last_mut().unwrap();
         let current_block = current_context.blocks.last_mut().unwrap();
@@ -33,9 +62,9 @@ impl Compiler {
         });
     }
 
-    fn next_register(&mut self) -> u8 {
+    fn next_register(&mut self) -> u32 {
         let current_context = self.contexts.last_mut().unwrap();
-        if current_context.register_count == 255 {
+        if current_context.register_count == u32::MAX {
             panic!("Ran out of registers");
         }
         let reg = current_context.register_count;
@@ -43,16 +72,69 @@ impl Compiler {
         return reg;
     }
 
-    fn reg_count(&self) -> u8 {
+    fn reg_count(&self) -> u32 {
         let current_context = self.contexts.last().unwrap();
         return current_context.register_count;
     }
 
-    fn set_reg_count(&mut self, reg_count: u8) {
+    fn set_reg_count(&mut self, reg_count: u32) {
         let current_context = self.contexts.last_mut().unwrap();
         current_context.register_count = reg_count;
     }
 
+    fn enter_loop(&mut self) {
+        self.contexts.last_mut().unwrap().loop_count += 1;
+    }
+
+    fn leave_loop(&mut self) {
+        self.contexts.last_mut().unwrap().loop_count -= 1;
+    }
+
+    fn enter_try(&mut self, registered: bool, finally_body: &Option<Box<Stmt>>) {
+        let current_context = self.contexts.last_mut().unwrap();
+        let loop_count = current_context.loop_count;
+        current_context.tries.push(TryContext {
+            registered,
+            finally_body: finally_body.clone(),
+            loop_count,
+        });
+    }
+
+    fn leave_try(&mut self) {
+        self.contexts.last_mut().unwrap().tries.pop();
+    }
+
+    // Deregisters handlers and runs finally blocks of the try statements being
+    // left by a jump. Returns from the function when inside_loop is false,
+    // otherwise break or continue on the innermost loop.
+    fn unwind_tries(&mut self, chunk: &mut Chunk, inside_loop: bool) {
+        let loop_count = self.contexts.last().unwrap().loop_count;
+        let tries = self.contexts.last().unwrap().tries.clone();
+        for (ix, try_ctx) in tries.iter().enumerate().rev() {
+            if inside_loop && try_ctx.loop_count < loop_count {
+                break;
+            }
+            if try_ctx.registered {
+                chunk.push(
+                    Instruction {
+                        opcode: OpCode::DeregisterTryCatch,
+                        a: 0,
+                        b: 0,
+                        c: 0,
+                    },
+                    None,
+                );
+            }
+            if let Some(finally_body) = &try_ctx.finally_body {
+                // The finally block only sees the try statements around it
+                let inner = self.contexts.last_mut().unwrap().tries.split_off(ix);
+                let finally_chunk = finally_body.accept(self);
+                chunk.append(&mut finally_chunk.instructions.clone());
+                self.contexts.last_mut().unwrap().tries.extend(inner);
+            }
+        }
+    }
+
     fn enter_block(&mut self) {
         let current_context = self.contexts.last_mut().unwrap();
         current_context.blocks.push(Block { locals: vec![] });
@@ -71,10 +153,11 @@ impl Compiler {
             chunks: vec![],
             blocks: vec![Block { locals: vec![] }],
             upvalues: vec![],
+            tries: vec![],
         });
     }
 
-    pub fn leave_function(&mut self, param_count: usize) -> u16 {
+    pub fn leave_function(&mut self, param_count: usize) -> u32 {
         let current_context = self.contexts.pop().unwrap();
         let prototype_ix = self.prototypes.len();
         let mut instructions: Vec<InstSrc> = current_context
@@ -99,12 +182,13 @@ impl Compiler {
             instruction_data: instructions.iter().map(|i| i.src.clone()).collect(),
             param_count: param_count,
             name: current_context.name,
-            file_path: crate::interpreter::get_absolute_path(),
+            file_path: self.file_path.clone(),
+            source: self.source.clone(),
         });
-        return prototype_ix as u16;
+        prototype_ix as u32
     }
 
-    fn result_register(&self) -> u8 {
+    fn result_register(&self) -> u32 {
         let current_context = self.contexts.last().unwrap();
         if current_context.chunks.is_empty() {
             // TODO: why is this needed?
@@ -113,7 +197,7 @@ impl Compiler {
         return current_context.chunks.last().unwrap().result_register;
     }
 
-    fn get_var_register_by_context(&self, context: &FnContext, var_name: &String) -> Option<u8> {
+    fn get_var_register_by_context(&self, context: &FnContext, var_name: &String) -> Option<u32> {
         context
             .blocks
             .iter()
@@ -133,11 +217,11 @@ impl Compiler {
             })
     }
 
-    fn get_var_register(&self, var_name: &String) -> Option<u8> {
+    fn get_var_register(&self, var_name: &String) -> Option<u32> {
         self.get_var_register_by_context(self.contexts.last().unwrap(), var_name)
     }
 
-    fn get_upvalue(&mut self, var_name: &String) -> Option<u8> {
+    fn get_upvalue(&mut self, var_name: &String) -> Option<u32> {
         // Edge case - when variable cannot be found
         if self.contexts.is_empty() || self.is_global_context() {
             return None;
@@ -148,7 +232,7 @@ impl Compiler {
         upvalue
     }
 
-    fn _get_upvalue(&mut self, previous_context: &mut FnContext, var_name: &String) -> Option<u8> {
+    fn _get_upvalue(&mut self, previous_context: &mut FnContext, var_name: &String) -> Option<u32> {
         // Edge case - when variable cannot be found
         if self.contexts.is_empty() || self.is_global_context() {
             return None;
@@ -174,15 +258,7 @@ impl Compiler {
     }
 
     pub fn is_builtin_var(&self, var_name: String) -> bool {
-        return var_name == "io".to_string()
-            || var_name == "strings".to_string()
-            || var_name == "type".to_string()
-            || var_name == "env".to_string()
-            || var_name == "import".to_string()
-            || var_name == "net".to_string()
-            || var_name == "re".to_string()
-            || var_name == "process".to_string()
-            || var_name == "lists".to_string();
+        self.builtins.contains(&var_name)
     }
 
     pub fn is_global_var(&self, var_name: String) -> bool {
@@ -193,7 +269,7 @@ impl Compiler {
         return self.contexts.len() == 1 && self.contexts.last().unwrap().blocks.len() == 1;
     }
 
-    pub fn compile(&mut self, stmts: Vec<Stmt>) {
+    pub fn compile(&mut self, stmts: Vec<Stmt>) -> Result<(), Vec<CompileErr>> {
         if self.contexts.is_empty() {
             self.contexts.push(FnContext {
                 chunks: vec![],
@@ -202,24 +278,51 @@ impl Compiler {
                 loop_count: 0,
                 blocks: vec![Block { locals: vec![] }],
                 upvalues: vec![],
+                tries: vec![],
             });
         }
         for stmt in stmts {
             let chunk = stmt.accept(self);
             self.add_chunk(chunk);
         }
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        Ok(())
     }
 
-    pub fn compilation_error(&self, msg: RuntimeErr, token_data: Option<TokenData>) {
-        if let Some(tk) = token_data {
-            print!(
-                "Compilation Error on line {}\n\t{}: {}\n",
-                tk.line, msg.msg, tk.lexeme,
-            );
-        } else {
-            print!("Compilation Error\n\t{}\n", msg.msg);
+    pub fn compilation_error(&mut self, mut msg: RuntimeErr, token_data: Option<TokenData>) {
+        if let Some(tk) = &token_data {
+            msg.location = Some(Box::new(Location {
+                file: self.file_path.clone(),
+                line: tk.line,
+                column: tk.column,
+                source_line: self.source.as_ref().and_then(|s| source_line(s, tk.line)),
+            }));
+            msg.token = Some(Box::new(tk.clone()));
+        }
+        self.errors.push(CompileErr {
+            err: msg,
+            token: token_data,
+        });
+    }
+
+    // Placeholder for an expression that failed to compile, keeps the
+    // rest of the code generation going so that all errors get reported
+    fn error_chunk(&mut self) -> Chunk {
+        let reg = self.next_register();
+        Chunk {
+            instructions: vec![InstSrc {
+                inst: Instruction {
+                    opcode: OpCode::LoadNil,
+                    a: reg,
+                    b: 0,
+                    c: 0,
+                },
+                src: None,
+            }],
+            result_register: reg,
         }
-        std::process::exit(0);
     }
 
     fn access_collection(&mut self, expr: &AccessExpr, access_op: OpCode) -> Chunk {
@@ -290,15 +393,10 @@ impl Compiler {
                 token_data.clone(),
             );
             let one_register = self.next_register();
-            let constant_ix = self.constants.len() as u16;
+            let constant_ix = self.constants.len() as u32;
             self.constants.push(Literal::Number(1.0));
             chunk.push(
-                Instruction {
-                    opcode: OpCode::LoadK,
-                    a: one_register,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                Instruction::abx(OpCode::LoadK, one_register, constant_ix),
                 token_data.clone(),
             );
             if !expr.first.is_empty() {
@@ -426,7 +524,7 @@ impl Compiler {
                             opcode: OpCode::Addi,
                             a: result_register,
                             b: left_chunk.result_register,
-                            c: n as u8,
+                            c: n as u32,
                         },
                         Some(operator.clone()),
                     );
@@ -436,44 +534,223 @@ impl Compiler {
         }
         return None;
     }
+
+    // Compound assignments read the target once, apply the operator and store
+    // the result back, which is also the value of the expression
+    fn compound_assign(&mut self, expr: &AssignExpr, operator: &TokenData) -> Chunk {
+        let name = expr.name.lexeme.to_string();
+        if self.get_var_register(&name).is_none()
+            && self.get_upvalue(&name).is_none()
+            && !self.is_global_var(name.clone())
+        {
+            self.compilation_error(ERR_UNDEFINED_VAR, Some(expr.name.clone()));
+            return self.error_chunk();
+        }
+        if let Some(access) = &expr.access {
+            return self.compound_access(access, operator, &expr.value);
+        }
+        let token_data = Some(expr.name.clone());
+        let var_expr = Expr::Var(VarExpr {
+            name: Some(expr.name.clone()),
+        });
+        let mut chunk = var_expr.accept(self);
+        let current = chunk.result_register;
+        self.compound_value(&mut chunk, current, operator, &expr.value);
+
+        if let Some(reg) = self.get_var_register(&name) {
+            // Locals get the result written straight to their register
+            chunk.instructions.last_mut().unwrap().inst.a = reg;
+            chunk.result_register = reg;
+        } else if let Some(upvalue_ix) = self.get_upvalue(&name) {
+            chunk.push(
+                Instruction {
+                    opcode: OpCode::SetUpval,
+                    a: chunk.result_register,
+                    b: upvalue_ix,
+                    c: 0,
+                },
+                token_data,
+            );
+        } else {
+            let constant_ix = self.constants.len() as u32;
+            self.constants.push(Literal::String(name));
+            chunk.push(
+                Instruction::abx(OpCode::SetGlobal, chunk.result_register, constant_ix),
+                token_data,
+            );
+        }
+        chunk
+    }
+
+    fn compound_set(&mut self, expr: &SetExpr, operator: &TokenData) -> Chunk {
+        if let Some(access) = &expr.access {
+            return self.compound_access(access, operator, &expr.value);
+        }
+        let mut chunk = expr.object.accept(self);
+        let obj_register = chunk.result_register;
+        let key_register = self.next_register();
+        let constant_ix = self.constants.len() as u32;
+        self.constants
+            .push(Literal::String(expr.name.lexeme.to_string()));
+        chunk.push(
+            Instruction::abx(OpCode::LoadK, key_register, constant_ix),
+            Some(expr.name.clone()),
+        );
+        let current = self.next_register();
+        chunk.push(
+            Instruction {
+                opcode: OpCode::GetObj,
+                a: current,
+                b: obj_register,
+                c: key_register,
+            },
+            Some(expr.name.clone()),
+        );
+        self.compound_value(&mut chunk, current, operator, &expr.value);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::SetObj,
+                a: obj_register,
+                b: key_register,
+                c: chunk.result_register,
+            },
+            Some(expr.name.clone()),
+        );
+        chunk
+    }
+
+    fn compound_access(&mut self, access: &AccessExpr, operator: &TokenData, value: &Expr) -> Chunk {
+        let mut chunk = access.object.accept(self);
+        let obj_register = chunk.result_register;
+        let key_chunk = access.first.accept(self);
+        chunk.append(&mut key_chunk.instructions.clone());
+        let current = self.next_register();
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Access,
+                a: current,
+                b: obj_register,
+                c: key_chunk.result_register,
+            },
+            Some(access.brace.clone()),
+        );
+        self.compound_value(&mut chunk, current, operator, value);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Set,
+                a: obj_register,
+                b: key_chunk.result_register,
+                c: chunk.result_register,
+            },
+            Some(access.brace.clone()),
+        );
+        chunk
+    }
+
+    // Applies the operator of a compound assignment to the value in the current register.
+    // Adding or subtracting small integer literals uses Addi and Subi
+    fn compound_value(&mut self, chunk: &mut Chunk, current: u32, operator: &TokenData, value: &Expr) {
+        let result_register = self.next_register();
+        if let Expr::Literal(LiteralExpr {
+            value: Literal::Number(n),
+        }) = value
+        {
+            let immediate = match operator.token {
+                Token::PlusEqual => Some(OpCode::Addi),
+                Token::MinusEqual => Some(OpCode::Subi),
+                _ => None,
+            };
+            if let Some(opcode) = immediate {
+                if n.fract() == 0.0 && *n >= 0.0 && *n < 256.0 {
+                    chunk.push(
+                        Instruction {
+                            opcode,
+                            a: result_register,
+                            b: current,
+                            c: *n as u32,
+                        },
+                        Some(operator.clone()),
+                    );
+                    chunk.result_register = result_register;
+                    return;
+                }
+            }
+        }
+        let value_chunk = value.accept(self);
+        chunk.append(&mut value_chunk.instructions.clone());
+        let opcode = match operator.token {
+            Token::PlusEqual => OpCode::Add,
+            Token::MinusEqual => OpCode::Sub,
+            Token::StarEqual => OpCode::Mul,
+            Token::SlashEqual => OpCode::Div,
+            Token::ModEqual => OpCode::Mod,
+            Token::PowerEqual => OpCode::Pow,
+            _ => unreachable!(),
+        };
+        chunk.push(
+            Instruction {
+                opcode,
+                a: result_register,
+                b: current,
+                c: value_chunk.result_register,
+            },
+            Some(operator.clone()),
+        );
+        chunk.result_register = result_register;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpvalueRef {
     pub is_local: bool,
-    pub index: u8,
+    pub index: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FnPrototype {
     pub instructions: Vec<Instruction>,
-    pub register_count: u8,
+    pub register_count: u32,
     pub upvalues: Vec<UpvalueRef>,
     pub instruction_data: Vec<Option<TokenData>>,
     pub param_count: usize,
     pub name: String,
     pub file_path: String,
+    #[serde(skip)]
+    pub source: Option<Rc<str>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FnContext {
     pub name: String,
-    pub loop_count: u8,
-    pub register_count: u8,
+    pub loop_count: usize,
+    pub register_count: u32,
     pub chunks: Vec<Chunk>,
     pub blocks: Vec<Block>,
     pub upvalues: Vec<UpvalueRef>,
+    // Try statements being compiled, innermost last
+    #[serde(skip)]
+    pub tries: Vec<TryContext>,
+}
+
+/// Try statement that has to be cleaned up by return, break or continue.
+#[derive(Debug, Clone)]
+pub struct TryContext {
+    // Whether an exception handler is registered at this point
+    pub registered: bool,
+    pub finally_body: Option<Box<Stmt>>,
+    // Loop nesting when the try started, loops inside it don't leave the try
+    pub loop_count: usize,
 }
 
 impl FnContext {
-    pub fn add_upvalue(&mut self, index: u8, is_local: bool) -> u8 {
+    pub fn add_upvalue(&mut self, index: u32, is_local: bool) -> u32 {
         for (i, up) in self.upvalues.iter().enumerate() {
             if up.is_local == is_local && up.index == index {
-                return i as u8;
+                return i as u32;
             }
         }
 
-        if self.upvalues.len() == 255 {
+        if self.upvalues.len() == u32::MAX as usize {
             panic!("Too many upvalues");
         }
 
@@ -481,7 +758,7 @@ impl FnContext {
             is_local: is_local,
             index: index,
         });
-        return (self.upvalues.len() - 1) as u8;
+        (self.upvalues.len() - 1) as u32
     }
 }
 
@@ -493,7 +770,7 @@ pub struct Block {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Local {
     pub var_name: String,
-    pub reg: u8,
+    pub reg: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -505,7 +782,7 @@ pub struct InstSrc {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
     pub instructions: Vec<InstSrc>,
-    pub result_register: u8,
+    pub result_register: u32,
 }
 
 impl Chunk {
@@ -516,6 +793,12 @@ impl Chunk {
     pub fn append(&mut self, instructions: &mut Vec<InstSrc>) {
         self.instructions.append(instructions);
     }
+
+    // Branching code can skip its last instruction, which then
+    // can't be redirected to write somewhere else
+    pub fn has_jumps(&self) -> bool {
+        self.instructions.iter().any(|i| i.inst.opcode == OpCode::Jmp)
+    }
 }
 
 impl StmtVisitor<Chunk> for Compiler {
@@ -528,84 +811,98 @@ impl StmtVisitor<Chunk> for Compiler {
             result_register: 0,
             instructions: vec![],
         };
+        let simple_op = |opcode: OpCode, a: u32| Instruction {
+            opcode,
+            a,
+            b: 0,
+            c: 0,
+        };
+        // Jumps to the end of the statement, patched at the end
+        let mut end_jumps: Vec<usize> = vec![];
 
         // Stores the jump to the catch section, needs to be patched
-        chunk.push(
-            Instruction {
-                opcode: OpCode::RegisterTryCatch,
-                a: 0,
-                b: 0,
-                c: 0,
-            },
-            None,
-        );
+        chunk.push(simple_op(OpCode::RegisterTryCatch, 0), None);
 
+        self.enter_try(true, &stmt.finally_body);
         let try_body_chunk = stmt.try_body.accept(self);
+        self.leave_try();
         chunk.append(&mut try_body_chunk.instructions.clone());
-        chunk.push(
-            Instruction {
-                opcode: OpCode::DeregisterTryCatch,
-                a: 0,
-                b: 0,
-                c: 0,
-            },
-            None,
-        );
-
-        // Jmp to end, needs to be patched
-        chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: 0,
-                c: 0,
-            },
-            None,
-        );
+        chunk.push(simple_op(OpCode::DeregisterTryCatch, 0), None);
+        if let Some(finally_body) = &stmt.finally_body {
+            let finally_chunk = finally_body.accept(self);
+            chunk.append(&mut finally_chunk.instructions.clone());
+        }
+        end_jumps.push(chunk.instructions.len());
+        chunk.push(simple_op(OpCode::Jmp, 0), None);
 
         let catch_offset = chunk.instructions.len();
-        chunk.instructions[0].inst.b = (catch_offset >> 8) as u8;
-        chunk.instructions[0].inst.c = catch_offset as u8;
+        chunk.instructions[0].inst.set_bx(catch_offset as u32);
 
-        self.enter_block();
-        let catch_var_name_reg = self.next_register();
-        self.allocate_register(stmt.name.lexeme.to_string(), catch_var_name_reg);
-        chunk.push(
-            Instruction {
-                opcode: OpCode::GetExcept,
-                a: catch_var_name_reg,
-                b: 0,
-                c: 0,
-            },
-            Some(stmt.name.clone()),
-        );
+        if let (Some(name), Some(catch_body)) = (&stmt.name, &stmt.catch_body) {
+            self.enter_block();
+            let catch_var_name_reg = self.next_register();
+            self.allocate_register(name.lexeme.to_string(), catch_var_name_reg);
+            chunk.push(
+                simple_op(OpCode::GetExcept, catch_var_name_reg),
+                Some(name.clone()),
+            );
+            chunk.push(simple_op(OpCode::DeregisterTryCatch, 0), None);
+            if let Some(finally_body) = &stmt.finally_body {
+                // Errors inside the catch block still run the finally block
+                let register_ix = chunk.instructions.len();
+                chunk.push(simple_op(OpCode::RegisterTryCatch, 0), None);
+                self.enter_try(true, &stmt.finally_body);
+                let catch_body_chunk = catch_body.accept(self);
+                self.leave_try();
+                self.leave_block();
+                chunk.append(&mut catch_body_chunk.instructions.clone());
+                chunk.push(simple_op(OpCode::DeregisterTryCatch, 0), None);
+                let finally_chunk = finally_body.accept(self);
+                chunk.append(&mut finally_chunk.instructions.clone());
+                end_jumps.push(chunk.instructions.len());
+                chunk.push(simple_op(OpCode::Jmp, 0), None);
+
+                let handler_offset = chunk.instructions.len() - register_ix;
+                chunk.instructions[register_ix].inst.set_bx(handler_offset as u32);
+            } else {
+                let catch_body_chunk = catch_body.accept(self);
+                self.leave_block();
+                chunk.append(&mut catch_body_chunk.instructions.clone());
+            }
+        }
+
+        if let Some(finally_body) = &stmt.finally_body {
+            // Runs the finally block and raises the pending error again
+            let exception_reg = self.next_register();
+            chunk.push(simple_op(OpCode::GetExcept, exception_reg), None);
+            chunk.instructions.last_mut().unwrap().inst.b = 1;
+            chunk.push(simple_op(OpCode::DeregisterTryCatch, 0), None);
+            let finally_chunk = finally_body.accept(self);
+            chunk.append(&mut finally_chunk.instructions.clone());
+            chunk.push(simple_op(OpCode::Throw, exception_reg), None);
+        }
+
+        let end = chunk.instructions.len();
+        for jmp_ix in end_jumps {
+            let jmp_offset = end - jmp_ix;
+            chunk.instructions[jmp_ix].inst.set_sbx(jmp_offset as i32);
+        }
+
+        return chunk;
+    }
+
+    fn visit_throw_stmt(&mut self, stmt: &ThrowStmt) -> Chunk {
+        let mut chunk = stmt.value.accept(self);
         chunk.push(
             Instruction {
-                opcode: OpCode::DeregisterTryCatch,
-                a: 0,
+                opcode: OpCode::Throw,
+                a: chunk.result_register,
                 b: 0,
                 c: 0,
             },
-            None,
+            Some(stmt.keyword.clone()),
         );
-        let catch_body_chunk = stmt.catch_body.accept(self);
-        self.leave_block();
-        chunk.append(&mut catch_body_chunk.instructions.clone());
-
-        let jmp_offset = chunk.instructions.len() - catch_offset + 1;
-        for inst in &mut chunk.instructions {
-            if inst.inst.opcode == OpCode::Jmp
-                && inst.inst.a == 0
-                && inst.inst.b == 0
-                && inst.inst.c == 0
-            {
-                inst.inst.b = (jmp_offset >> 8) as u8;
-                inst.inst.c = jmp_offset as u8;
-                break;
-            }
-        }
-
-        return chunk;
+        chunk
     }
 
     fn visit_classic_for_stmt(&mut self, stmt: &ClassicForStmt) -> Chunk {
@@ -620,7 +917,9 @@ impl StmtVisitor<Chunk> for Compiler {
                 .append(&mut init_chunk.instructions.clone());
         }
         let cond_chunk = stmt.condition.accept(self);
+        self.enter_loop();
         let mut body_chunk = stmt.body.accept(self);
+        self.leave_loop();
         let inc_chunk = stmt.increment.accept(self);
         body_chunk
             .instructions
@@ -637,43 +936,32 @@ impl StmtVisitor<Chunk> for Compiler {
             },
             Some(stmt.keyword.clone()),
         );
-        let jump_size = (body_chunk.instructions.len() + 2) as i16;
+        let jump_size = (body_chunk.instructions.len() + 2) as i32;
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (jump_size >> 8) as u8,
-                c: jump_size as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, jump_size),
             Some(stmt.keyword.clone()),
         );
         chunk
             .instructions
             .append(&mut body_chunk.instructions.clone());
         let loop_size =
-            -((body_chunk.instructions.len() + cond_chunk.instructions.len() + 2) as i16);
+            -((body_chunk.instructions.len() + cond_chunk.instructions.len() + 2) as i32);
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (loop_size >> 8) as u8,
-                c: loop_size as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, loop_size),
             Some(stmt.keyword.clone()),
         );
-        // Patch continue and break
+        // Patch continue and break, continue runs the increment before checking again
         let chunk_size = chunk.instructions.len();
+        let increment_ix = chunk_size - 1 - inc_chunk.instructions.len();
         for (i, inst) in chunk.instructions.iter_mut().enumerate() {
             if inst.inst.is_continue() {
-                let jump_offset = -(i as i64);
+                let jump_offset = increment_ix as i64 - i as i64;
                 inst.inst.a = 0;
-                inst.inst.b = (jump_offset >> 8) as u8;
-                inst.inst.c = jump_offset as u8;
+                inst.inst.set_sbx(jump_offset as i32);
             } else if inst.inst.is_break() {
                 let jump_offset = chunk_size - i;
                 inst.inst.a = 0;
-                inst.inst.b = (jump_offset >> 8) as u8;
-                inst.inst.c = jump_offset as u8;
+                inst.inst.set_sbx(jump_offset as i32);
             }
         }
         return chunk;
@@ -684,17 +972,12 @@ impl StmtVisitor<Chunk> for Compiler {
         let length_reg = self.next_register();
         let cond_reg = self.next_register();
         let element_reg = self.next_register();
-        let constant_ix = self.constants.len() as u16;
+        let constant_ix = self.constants.len() as u32;
         self.constants.push(Literal::Number(0.0));
         let mut chunk = Chunk {
             result_register: 0,
             instructions: vec![InstSrc {
-                inst: Instruction {
-                    opcode: OpCode::LoadK,
-                    a: counter_reg,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                inst: Instruction::abx(OpCode::LoadK, counter_reg, constant_ix),
                 src: Some(stmt.keyword.clone()),
             }],
         };
@@ -744,7 +1027,7 @@ impl StmtVisitor<Chunk> for Compiler {
                         opcode: OpCode::GetIteri,
                         a: var_reg,
                         b: element_reg,
-                        c: i as u8,
+                        c: i as u32,
                     },
                     Some(stmt.keyword.clone()),
                 )
@@ -762,7 +1045,9 @@ impl StmtVisitor<Chunk> for Compiler {
                 Some(stmt.keyword.clone()),
             );
         }
+        self.enter_loop();
         let loop_chunk = stmt.body.accept(self);
+        self.leave_loop();
         body_chunk.result_register = loop_chunk.result_register;
         body_chunk.append(&mut loop_chunk.instructions.clone());
         body_chunk.push(
@@ -790,41 +1075,30 @@ impl StmtVisitor<Chunk> for Compiler {
             },
             Some(stmt.keyword.clone()),
         );
-        let jump_size = (body_chunk.instructions.len() + 2) as i16;
+        let jump_size = (body_chunk.instructions.len() + 2) as i32;
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (jump_size >> 8) as u8,
-                c: jump_size as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, jump_size),
             Some(stmt.keyword.clone()),
         );
         chunk.append(&mut body_chunk.instructions.clone());
         let loop_size =
-            -((body_chunk.instructions.len() + cond_chunk.instructions.len() + 2) as i16);
+            -((body_chunk.instructions.len() + cond_chunk.instructions.len() + 2) as i32);
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (loop_size >> 8) as u8,
-                c: loop_size as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, loop_size),
             Some(stmt.keyword.clone()),
         );
-        // Patch continue and break
+        // Patch continue and break, continue advances the counter before checking again
         let chunk_size = chunk.instructions.len();
+        let increment_ix = chunk_size - 2;
         for (i, inst) in chunk.instructions.iter_mut().enumerate() {
             if inst.inst.is_continue() {
-                let jump_offset = -(i as i64);
+                let jump_offset = increment_ix as i64 - i as i64;
                 inst.inst.a = 0;
-                inst.inst.b = (jump_offset >> 8) as u8;
-                inst.inst.c = jump_offset as u8;
+                inst.inst.set_sbx(jump_offset as i32);
             } else if inst.inst.is_break() {
                 let jump_offset = chunk_size - i;
                 inst.inst.a = 0;
-                inst.inst.b = (jump_offset >> 8) as u8;
-                inst.inst.c = jump_offset as u8;
+                inst.inst.set_sbx(jump_offset as i32);
             }
         }
         return chunk;
@@ -834,14 +1108,13 @@ impl StmtVisitor<Chunk> for Compiler {
         if self.is_global_context() {
             if self.globals.contains(&stmt.name.lexeme.to_string()) {
                 self.compilation_error(ERR_GLOBAL_ALREADY_DEFINED, Some(stmt.name.clone()));
-                unreachable!();
             }
             let mut chunk = Chunk {
                 result_register: 0,
                 instructions: vec![],
             };
             self.globals.insert(stmt.name.lexeme.to_string());
-            let constant_ix = self.constants.len() as u16;
+            let constant_ix = self.constants.len() as u32;
             self.constants
                 .push(Literal::String(stmt.name.lexeme.to_string()));
             if let Some(init) = &stmt.initializer {
@@ -849,12 +1122,7 @@ impl StmtVisitor<Chunk> for Compiler {
                 chunk.result_register = init_chunk.result_register;
                 chunk.append(&mut init_chunk.instructions.clone());
                 chunk.push(
-                    Instruction {
-                        opcode: OpCode::SetGlobal,
-                        a: init_chunk.result_register,
-                        b: (constant_ix >> 8) as u8,
-                        c: constant_ix as u8,
-                    },
+                    Instruction::abx(OpCode::SetGlobal, init_chunk.result_register, constant_ix),
                     Some(stmt.name.clone()),
                 );
             } else {
@@ -869,12 +1137,7 @@ impl StmtVisitor<Chunk> for Compiler {
                     Some(stmt.name.clone()),
                 );
                 chunk.push(
-                    Instruction {
-                        opcode: OpCode::SetGlobal,
-                        a: reg,
-                        b: (constant_ix >> 8) as u8,
-                        c: constant_ix as u8,
-                    },
+                    Instruction::abx(OpCode::SetGlobal, reg, constant_ix),
                     Some(stmt.name.clone()),
                 );
             }
@@ -912,7 +1175,9 @@ impl StmtVisitor<Chunk> for Compiler {
 
     fn visit_while_stmt(&mut self, stmt: &WhileStmt) -> Chunk {
         let cond_chunk = stmt.condition.accept(self);
+        self.enter_loop();
         let body_chunk = stmt.body.accept(self);
+        self.leave_loop();
         let mut chunk = Chunk {
             instructions: vec![],
             result_register: cond_chunk.result_register,
@@ -929,28 +1194,18 @@ impl StmtVisitor<Chunk> for Compiler {
             },
             Some(stmt.keyword.clone()),
         );
-        let jump_size = (body_chunk.instructions.len() + 2) as i16;
+        let jump_size = (body_chunk.instructions.len() + 2) as i32;
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (jump_size >> 8) as u8,
-                c: jump_size as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, jump_size),
             Some(stmt.keyword.clone()),
         );
         chunk
             .instructions
             .append(&mut body_chunk.instructions.clone());
         let loop_size =
-            -((body_chunk.instructions.len() + cond_chunk.instructions.len() + 2) as i16);
+            -((body_chunk.instructions.len() + cond_chunk.instructions.len() + 2) as i32);
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (loop_size >> 8) as u8,
-                c: loop_size as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, loop_size),
             Some(stmt.keyword.clone()),
         );
         // Patch continue and break
@@ -959,13 +1214,11 @@ impl StmtVisitor<Chunk> for Compiler {
             if inst.inst.is_continue() {
                 let jump_offset = -(i as i64);
                 inst.inst.a = 0;
-                inst.inst.b = (jump_offset >> 8) as u8;
-                inst.inst.c = jump_offset as u8;
+                inst.inst.set_sbx(jump_offset as i32);
             } else if inst.inst.is_break() {
                 let jump_offset = chunk_size - i;
                 inst.inst.a = 0;
-                inst.inst.b = (jump_offset >> 8) as u8;
-                inst.inst.c = jump_offset as u8;
+                inst.inst.set_sbx(jump_offset as i32);
             }
         }
         return chunk;
@@ -976,21 +1229,40 @@ impl StmtVisitor<Chunk> for Compiler {
             instructions: vec![],
             result_register: 0,
         };
+        let leaves_try = !self.contexts.last().unwrap().tries.is_empty();
         if let Some(val) = &stmt.value {
             let val_chunk: Chunk = val.accept(self);
             chunk
                 .instructions
                 .append(&mut val_chunk.instructions.clone());
+            let mut result_register = val_chunk.result_register;
+            if leaves_try {
+                // Keep the value safe from finally blocks
+                result_register = self.next_register();
+                chunk.push(
+                    Instruction {
+                        opcode: OpCode::Move,
+                        a: result_register,
+                        b: val_chunk.result_register,
+                        c: 0,
+                    },
+                    Some(stmt.keyword.clone()),
+                );
+                self.unwind_tries(&mut chunk, false);
+            }
             chunk.push(
                 Instruction {
                     opcode: OpCode::Return,
-                    a: val_chunk.result_register,
-                    b: val_chunk.result_register + 2,
+                    a: result_register,
+                    b: result_register + 2,
                     c: 0,
                 },
                 Some(stmt.keyword.clone()),
             );
         } else {
+            if leaves_try {
+                self.unwind_tries(&mut chunk, false);
+            }
             chunk.push(
                 Instruction {
                     opcode: OpCode::Return,
@@ -1005,33 +1277,39 @@ impl StmtVisitor<Chunk> for Compiler {
     }
 
     fn visit_break_stmt(&mut self, stmt: &BreakStmt) -> Chunk {
-        return Chunk {
-            instructions: vec![InstSrc {
-                inst: Instruction {
-                    opcode: OpCode::Jmp,
-                    a: JMP_BREAK,
-                    b: 0,
-                    c: 0,
-                },
-                src: Some(stmt.keyword.clone()),
-            }],
+        let mut chunk = Chunk {
+            instructions: vec![],
             result_register: 0,
         };
+        self.unwind_tries(&mut chunk, true);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Jmp,
+                a: JMP_BREAK,
+                b: 0,
+                c: 0,
+            },
+            Some(stmt.keyword.clone()),
+        );
+        chunk
     }
 
     fn visit_continue_stmt(&mut self, stmt: &ContinueStmt) -> Chunk {
-        return Chunk {
-            instructions: vec![InstSrc {
-                inst: Instruction {
-                    opcode: OpCode::Jmp,
-                    a: JMP_CONTINUE,
-                    b: 0,
-                    c: 0,
-                },
-                src: Some(stmt.keyword.clone()),
-            }],
+        let mut chunk = Chunk {
+            instructions: vec![],
             result_register: 0,
         };
+        self.unwind_tries(&mut chunk, true);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Jmp,
+                a: JMP_CONTINUE,
+                b: 0,
+                c: 0,
+            },
+            Some(stmt.keyword.clone()),
+        );
+        chunk
     }
 
     fn visit_if_stmt(&mut self, stmt: &IfStmt) -> Chunk {
@@ -1078,14 +1356,9 @@ impl StmtVisitor<Chunk> for Compiler {
             Some(stmt.keyword.clone()),
         );
         // Body length + 2 jmps
-        let mut jmp_offset = (if_body.len() + 2) as u16;
+        let mut jmp_offset = (if_body.len() + 2) as i32;
         chunk.push(
-            Instruction {
-                opcode: OpCode::Jmp,
-                a: 0,
-                b: (jmp_offset >> 8) as u8,
-                c: jmp_offset as u8,
-            },
+            Instruction::asbx(OpCode::Jmp, 0, jmp_offset),
             Some(stmt.keyword.clone()),
         );
         chunk.append(&mut if_body);
@@ -1114,14 +1387,9 @@ impl StmtVisitor<Chunk> for Compiler {
             );
             let elif_body = &elifs_body[i];
             // Body length + 2 jmps
-            jmp_offset = (elif_body.len() + 2) as u16;
+            jmp_offset = (elif_body.len() + 2) as i32;
             chunk.push(
-                Instruction {
-                    opcode: OpCode::Jmp,
-                    a: 0,
-                    b: (jmp_offset >> 8) as u8,
-                    c: jmp_offset as u8,
-                },
+                Instruction::asbx(OpCode::Jmp, 0, jmp_offset),
                 None,
             );
             chunk.append(&mut elif_body.clone());
@@ -1146,15 +1414,14 @@ impl StmtVisitor<Chunk> for Compiler {
             {
                 // Jump to the next instruction after this chunk
                 let jmp_offset = chunk_size - offset;
-                inst.inst.b = (jmp_offset >> 8) as u8;
-                inst.inst.c = jmp_offset as u8;
+                inst.inst.set_sbx(jmp_offset as i32);
             }
         }
         return chunk;
     }
 
     fn visit_fn_stmt(&mut self, stmt: &FnStmt) -> Chunk {
-        let result_register: u8 = self.next_register();
+        let result_register: u32 = self.next_register();
         if self.is_global_context() {
             self.globals.insert(stmt.name.lexeme.to_string());
         }
@@ -1200,12 +1467,7 @@ impl StmtVisitor<Chunk> for Compiler {
 
         let mut chunk = Chunk {
             instructions: vec![InstSrc {
-                inst: Instruction {
-                    opcode: OpCode::Closure,
-                    a: result_register,
-                    b: (prototype_ix >> 8) as u8,
-                    c: prototype_ix as u8,
-                },
+                inst: Instruction::abx(OpCode::Closure, result_register, prototype_ix),
                 src: Some(stmt.name.clone()),
             }],
             result_register: result_register,
@@ -1215,12 +1477,7 @@ impl StmtVisitor<Chunk> for Compiler {
             self.constants
                 .push(Literal::String(stmt.name.lexeme.to_string()));
             chunk.push(
-                Instruction {
-                    opcode: OpCode::SetGlobal,
-                    a: chunk.result_register,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                Instruction::abx(OpCode::SetGlobal, chunk.result_register, constant_ix as u32),
                 Some(stmt.name.clone()),
             );
         } else {
@@ -1230,7 +1487,7 @@ impl StmtVisitor<Chunk> for Compiler {
     }
 
     fn visit_class_stmt(&mut self, stmt: &ClassStmt) -> Chunk {
-        let result_register: u8 = self.next_register();
+        let result_register: u32 = self.next_register();
         if self.is_global_context() {
             self.globals
                 .insert(stmt.name.clone().unwrap().lexeme.to_string());
@@ -1240,17 +1497,12 @@ impl StmtVisitor<Chunk> for Compiler {
             result_register: result_register,
         };
         let tmp_reg = self.next_register();
-        let constant_ix = self.constants.len() as u16;
+        let constant_ix = self.constants.len() as u32;
         self.constants.push(Literal::String(
             stmt.name.clone().unwrap().lexeme.to_string(),
         ));
         chunk.push(
-            Instruction {
-                opcode: OpCode::LoadK,
-                a: tmp_reg,
-                b: (constant_ix >> 8) as u8,
-                c: constant_ix as u8,
-            },
+            Instruction::abx(OpCode::LoadK, tmp_reg, constant_ix),
             stmt.name.clone(),
         );
         let superclass_reg = if let Some(superclass) = &stmt.superclass {
@@ -1287,16 +1539,11 @@ impl StmtVisitor<Chunk> for Compiler {
             chunk
                 .instructions
                 .append(&mut met_chunk.instructions.clone());
-            let constant_ix = self.constants.len() as u16;
+            let constant_ix = self.constants.len() as u32;
             self.constants
                 .push(Literal::String(met.name.lexeme.to_string()));
             chunk.push(
-                Instruction {
-                    opcode: OpCode::LoadK,
-                    a: tmp_reg,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                Instruction::abx(OpCode::LoadK, tmp_reg, constant_ix),
                 stmt.name.clone(),
             );
             chunk.push(
@@ -1312,16 +1559,11 @@ impl StmtVisitor<Chunk> for Compiler {
         for met in &stmt.static_methods {
             let met_chunk = self.visit_fn_stmt(met);
             chunk.append(&mut met_chunk.instructions.clone());
-            let constant_ix = self.constants.len() as u16;
+            let constant_ix = self.constants.len() as u32;
             self.constants
                 .push(Literal::String(met.name.lexeme.to_string()));
             chunk.push(
-                Instruction {
-                    opcode: OpCode::LoadK,
-                    a: tmp_reg,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                Instruction::abx(OpCode::LoadK, tmp_reg, constant_ix),
                 stmt.name.clone(),
             );
             chunk.push(
@@ -1341,12 +1583,7 @@ impl StmtVisitor<Chunk> for Compiler {
                 stmt.name.clone().unwrap().lexeme.to_string(),
             ));
             chunk.push(
-                Instruction {
-                    opcode: OpCode::SetGlobal,
-                    a: chunk.result_register,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                Instruction::abx(OpCode::SetGlobal, chunk.result_register, constant_ix as u32),
                 stmt.name.clone(),
             );
         } else {
@@ -1361,7 +1598,7 @@ impl StmtVisitor<Chunk> for Compiler {
 
 impl ExprVisitor<Chunk> for Compiler {
     fn visit_function_expr(&mut self, expr: &FnExpr) -> Chunk {
-        let result_register: u8 = self.next_register();
+        let result_register: u32 = self.next_register();
         self.enter_function("".to_string());
         // FIXME: We need to skip first register because in fn_stmt we use
         // it for storing the function
@@ -1391,12 +1628,7 @@ impl ExprVisitor<Chunk> for Compiler {
         let token_data = expr.params.get(0).and_then(|p| Some(p.clone()));
         return Chunk {
             instructions: vec![InstSrc {
-                inst: Instruction {
-                    opcode: OpCode::Closure,
-                    a: result_register,
-                    b: (prototype_ix >> 8) as u8,
-                    c: prototype_ix as u8,
-                },
+                inst: Instruction::abx(OpCode::Closure, result_register, prototype_ix),
                 src: token_data.clone(),
             }],
             result_register: result_register,
@@ -1430,12 +1662,7 @@ impl ExprVisitor<Chunk> for Compiler {
             self.constants.push(Literal::String(var_name.clone()));
             return Chunk {
                 instructions: vec![InstSrc {
-                    inst: Instruction {
-                        opcode: OpCode::GetBuiltin,
-                        a: reg,
-                        b: (constant_ix >> 8) as u8,
-                        c: constant_ix as u8,
-                    },
+                    inst: Instruction::abx(OpCode::GetBuiltin, reg, constant_ix as u32),
                     src: expr.name.clone(),
                 }],
                 result_register: reg,
@@ -1446,19 +1673,14 @@ impl ExprVisitor<Chunk> for Compiler {
             self.constants.push(Literal::String(var_name.clone()));
             return Chunk {
                 instructions: vec![InstSrc {
-                    inst: Instruction {
-                        opcode: OpCode::GetGlobal,
-                        a: reg,
-                        b: (constant_ix >> 8) as u8,
-                        c: constant_ix as u8,
-                    },
+                    inst: Instruction::abx(OpCode::GetGlobal, reg, constant_ix as u32),
                     src: expr.name.clone(),
                 }],
                 result_register: reg,
             };
         }
         self.compilation_error(ERR_UNDEFINED_VAR, expr.name.clone());
-        unreachable!();
+        self.error_chunk()
     }
 
     fn visit_list_expr(&mut self, expr: &ListExpr) -> Chunk {
@@ -1533,6 +1755,9 @@ impl ExprVisitor<Chunk> for Compiler {
     }
 
     fn visit_assign_expr(&mut self, expr: &AssignExpr) -> Chunk {
+        if let Some(operator) = &expr.operator {
+            return self.compound_assign(expr, operator);
+        }
         let token_data = Some(expr.name.clone());
         if let Some(reg) = self.get_var_register(&expr.name.lexeme.to_string()) {
             let mut chunk = expr.value.accept(self);
@@ -1549,16 +1774,28 @@ impl ExprVisitor<Chunk> for Compiler {
                         b: access_chunk.result_register,
                         c: chunk.result_register,
                     },
-                    token_data.clone(),
+                    Some(access.brace.clone()),
                 );
             } else {
-                if !chunk.instructions.is_empty() {
+                if !chunk.instructions.is_empty() && !chunk.has_jumps() {
                     let inst = chunk.instructions.last_mut().unwrap();
                     if inst.inst.opcode == OpCode::Call {
                         inst.inst.c = reg + 1;
                     } else {
                         inst.inst.a = reg;
                     }
+                } else if chunk.result_register != reg {
+                    // Value is already held by a local or produced by a branching
+                    // expression, copy it
+                    chunk.push(
+                        Instruction {
+                            opcode: OpCode::Move,
+                            a: reg,
+                            b: chunk.result_register,
+                            c: 0,
+                        },
+                        token_data.clone(),
+                    );
                 }
                 chunk.result_register = reg;
             }
@@ -1580,16 +1817,28 @@ impl ExprVisitor<Chunk> for Compiler {
                         b: access_chunk.result_register,
                         c: chunk.result_register,
                     },
-                    token_data.clone(),
+                    Some(access.brace.clone()),
                 );
             } else {
-                if !chunk.instructions.is_empty() {
+                if !chunk.instructions.is_empty() && !chunk.has_jumps() {
                     let inst = chunk.instructions.last_mut().unwrap();
                     if inst.inst.opcode == OpCode::Call {
                         inst.inst.c = reg + 1;
                     } else {
                         inst.inst.a = reg;
                     }
+                } else if chunk.result_register != reg {
+                    // Value is already held by a local or produced by a branching
+                    // expression, copy it
+                    chunk.push(
+                        Instruction {
+                            opcode: OpCode::Move,
+                            a: reg,
+                            b: chunk.result_register,
+                            c: 0,
+                        },
+                        token_data.clone(),
+                    );
                 }
                 chunk.push(
                     Instruction {
@@ -1623,24 +1872,31 @@ impl ExprVisitor<Chunk> for Compiler {
                         b: access_chunk.result_register,
                         c: chunk.result_register,
                     },
-                    token_data.clone(),
+                    Some(access.brace.clone()),
                 );
             } else {
-                if !chunk.instructions.is_empty() {
+                if !chunk.instructions.is_empty() && !chunk.has_jumps() {
                     let inst = chunk.instructions.last_mut().unwrap();
                     if inst.inst.opcode == OpCode::Call {
                         inst.inst.c = reg + 1;
                     } else {
                         inst.inst.a = reg;
                     }
+                } else if chunk.result_register != reg {
+                    // Value is already held by a local or produced by a branching
+                    // expression, copy it
+                    chunk.push(
+                        Instruction {
+                            opcode: OpCode::Move,
+                            a: reg,
+                            b: chunk.result_register,
+                            c: 0,
+                        },
+                        token_data.clone(),
+                    );
                 }
                 chunk.push(
-                    Instruction {
-                        opcode: OpCode::SetGlobal,
-                        a: reg,
-                        b: (constant_ix >> 8) as u8,
-                        c: constant_ix as u8,
-                    },
+                    Instruction::abx(OpCode::SetGlobal, reg, constant_ix as u32),
                     token_data.clone(),
                 );
                 chunk.result_register = reg;
@@ -1649,7 +1905,7 @@ impl ExprVisitor<Chunk> for Compiler {
             return chunk;
         }
         self.compilation_error(ERR_UNDEFINED_VAR, Some(expr.name.clone()));
-        unreachable!();
+        self.error_chunk()
     }
 
     fn visit_access_expr(&mut self, expr: &AccessExpr) -> Chunk {
@@ -1736,7 +1992,7 @@ impl ExprVisitor<Chunk> for Compiler {
             chunk.push(
                 Instruction {
                     opcode: OpCode::Move,
-                    a: start_reg + i as u8,
+                    a: start_reg + i as u32,
                     b: arg_chunk.result_register,
                     c: 0,
                 },
@@ -1748,7 +2004,7 @@ impl ExprVisitor<Chunk> for Compiler {
             Instruction {
                 opcode: OpCode::Call,
                 a: fn_register,
-                b: expr.arguments.len() as u8 + 1,
+                b: expr.arguments.len() as u32 + 1,
                 c: chunk.result_register + 1,
             },
             token_data.clone(),
@@ -1759,16 +2015,11 @@ impl ExprVisitor<Chunk> for Compiler {
     fn visit_get_expr(&mut self, expr: &GetExpr) -> Chunk {
         let mut chunk = expr.object.accept(self);
         let result_register = self.next_register();
-        let constant_ix = self.constants.len() as u16;
+        let constant_ix = self.constants.len() as u32;
         let val = Literal::String(expr.name.lexeme.to_string());
         self.constants.push(val);
         chunk.push(
-            Instruction {
-                opcode: OpCode::LoadK,
-                a: result_register,
-                b: (constant_ix >> 8) as u8,
-                c: constant_ix as u8,
-            },
+            Instruction::abx(OpCode::LoadK, result_register, constant_ix),
             Some(expr.name.clone()),
         );
         chunk.push(
@@ -1785,6 +2036,9 @@ impl ExprVisitor<Chunk> for Compiler {
     }
 
     fn visit_set_expr(&mut self, expr: &SetExpr) -> Chunk {
+        if let Some(operator) = &expr.operator {
+            return self.compound_set(expr, operator);
+        }
         let mut chunk = Chunk {
             instructions: vec![],
             result_register: 0,
@@ -1803,16 +2057,11 @@ impl ExprVisitor<Chunk> for Compiler {
             chunk.append(&mut obj_chunk.instructions.clone());
             chunk.result_register = obj_chunk.result_register;
             let tmp_register = self.next_register();
-            let constant_ix = self.constants.len() as u16;
+            let constant_ix = self.constants.len() as u32;
             let val = Literal::String(expr.name.lexeme.to_string());
             self.constants.push(val);
             chunk.push(
-                Instruction {
-                    opcode: OpCode::LoadK,
-                    a: tmp_register,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                Instruction::abx(OpCode::LoadK, tmp_register, constant_ix),
                 Some(expr.name.clone()),
             );
             chunk.push(
@@ -1833,16 +2082,11 @@ impl ExprVisitor<Chunk> for Compiler {
             instructions: vec![],
             result_register: self.next_register(),
         };
-        let constant_ix = self.constants.len() as u16;
+        let constant_ix = self.constants.len() as u32;
         self.constants
             .push(Literal::String(expr.method.lexeme.to_string()));
         chunk.push(
-            Instruction {
-                opcode: OpCode::LoadK,
-                a: chunk.result_register,
-                b: (constant_ix >> 8) as u8,
-                c: constant_ix as u8,
-            },
+            Instruction::abx(OpCode::LoadK, chunk.result_register, constant_ix),
             Some(expr.method.clone()),
         );
         chunk.push(
@@ -1863,16 +2107,11 @@ impl ExprVisitor<Chunk> for Compiler {
 
     fn visit_literal_expr(&mut self, expr: &LiteralExpr) -> Chunk {
         let result_register = self.next_register();
-        let constant_ix = self.constants.len() as u16;
+        let constant_ix = self.constants.len() as u32;
         self.constants.push(expr.value.clone());
         let chunk = Chunk {
             instructions: vec![InstSrc {
-                inst: Instruction {
-                    opcode: OpCode::LoadK,
-                    a: result_register,
-                    b: (constant_ix >> 8) as u8,
-                    c: constant_ix as u8,
-                },
+                inst: Instruction::abx(OpCode::LoadK, result_register, constant_ix),
                 src: None,
             }],
             result_register: result_register,
@@ -1880,6 +2119,44 @@ impl ExprVisitor<Chunk> for Compiler {
         return chunk;
     }
 
+    fn visit_fstring_expr(&mut self, expr: &FStringExpr) -> Chunk {
+        let mut chunk = Chunk {
+            instructions: vec![],
+            result_register: 0,
+        };
+        // Parts are moved to consecutive registers and joined by a single Concat
+        let start_reg = self.reg_count();
+        for _ in 0..(expr.parts.len()) {
+            self.next_register();
+        }
+        for (i, part) in expr.parts.iter().enumerate() {
+            let part_chunk = part.accept(self);
+            chunk
+                .instructions
+                .append(&mut part_chunk.instructions.clone());
+            chunk.push(
+                Instruction {
+                    opcode: OpCode::Move,
+                    a: start_reg + i as u32,
+                    b: part_chunk.result_register,
+                    c: 0,
+                },
+                None,
+            );
+        }
+        chunk.result_register = self.next_register();
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Concat,
+                a: chunk.result_register,
+                b: start_reg,
+                c: expr.parts.len() as u32,
+            },
+            Some(expr.token.clone()),
+        );
+        chunk
+    }
+
     fn visit_logical_expr(&mut self, expr: &LogicalExpr) -> Chunk {
         let left_chunk = expr.left.accept(self);
         let right_chunk = expr.right.accept(self);
@@ -1900,14 +2177,9 @@ impl ExprVisitor<Chunk> for Compiler {
                 },
                 Some(expr.operator.clone()),
             );
-            let jump_size = (right_chunk.instructions.len() + 2) as u16;
+            let jump_size = (right_chunk.instructions.len() + 2) as i32;
             chunk.push(
-                Instruction {
-                    opcode: OpCode::Jmp,
-                    a: 0,
-                    b: (jump_size >> 8) as u8,
-                    c: jump_size as u8,
-                },
+                Instruction::asbx(OpCode::Jmp, 0, jump_size),
                 Some(expr.operator.clone()),
             );
             chunk.append(&mut right_chunk.instructions.clone());
@@ -1930,14 +2202,9 @@ impl ExprVisitor<Chunk> for Compiler {
                 },
                 Some(expr.operator.clone()),
             );
-            let jump_size = (right_chunk.instructions.len() + 2) as u16;
+            let jump_size = (right_chunk.instructions.len() + 2) as i32;
             chunk.push(
-                Instruction {
-                    opcode: OpCode::Jmp,
-                    a: 0,
-                    b: (jump_size >> 8) as u8,
-                    c: jump_size as u8,
-                },
+                Instruction::asbx(OpCode::Jmp, 0, jump_size),
                 Some(expr.operator.clone()),
             );
             chunk.append(&mut right_chunk.instructions.clone());
@@ -1954,6 +2221,59 @@ impl ExprVisitor<Chunk> for Compiler {
         return chunk;
     }
 
+    fn visit_conditional_expr(&mut self, expr: &ConditionalExpr) -> Chunk {
+        let result_register = self.next_register();
+        let mut cond_chunk = expr.condition.accept(self);
+        let mut then_chunk = expr.then_branch.accept(self);
+        let mut else_chunk = expr.else_branch.accept(self);
+        let token_data = Some(expr.question.clone());
+        let mut chunk = Chunk {
+            instructions: vec![],
+            result_register,
+        };
+        chunk.append(&mut cond_chunk.instructions);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Test,
+                a: cond_chunk.result_register,
+                b: cond_chunk.result_register,
+                c: 0,
+            },
+            token_data.clone(),
+        );
+        // Skip the then branch, its move and its jump to the end
+        chunk.push(
+            Instruction::asbx(OpCode::Jmp, 0, (then_chunk.instructions.len() + 3) as i32),
+            token_data.clone(),
+        );
+        chunk.append(&mut then_chunk.instructions);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Move,
+                a: result_register,
+                b: then_chunk.result_register,
+                c: 0,
+            },
+            token_data.clone(),
+        );
+        // Skip the else branch and its move
+        chunk.push(
+            Instruction::asbx(OpCode::Jmp, 0, (else_chunk.instructions.len() + 2) as i32),
+            token_data.clone(),
+        );
+        chunk.append(&mut else_chunk.instructions);
+        chunk.push(
+            Instruction {
+                opcode: OpCode::Move,
+                a: result_register,
+                b: else_chunk.result_register,
+                c: 0,
+            },
+            token_data,
+        );
+        chunk
+    }
+
     fn visit_this_expr(&mut self, expr: &ThisExpr) -> Chunk {
         let reg = self.next_register();
         Chunk {
@@ -1995,19 +2315,20 @@ impl ExprVisitor<Chunk> for Compiler {
 impl StmtAcceptor<Chunk> for Stmt {
     fn accept(&self, visitor: &mut dyn StmtVisitor<Chunk>) -> Chunk {
         match self {
-            Stmt::Fn(stmt) => visitor.visit_fn_stmt(&stmt),
-            Stmt::Let(stmt) => visitor.visit_let_stmt(&stmt),
-            Stmt::Block(stmt) => visitor.visit_block_stmt(&stmt),
-            Stmt::Class(stmt) => visitor.visit_class_stmt(&stmt),
-            Stmt::ClassicFor(stmt) => visitor.visit_classic_for_stmt(&stmt),
-            Stmt::EnhancedFor(stmt) => visitor.visit_enhanced_for_stmt(&stmt),
-            Stmt::While(stmt) => visitor.visit_while_stmt(&stmt),
-            Stmt::If(stmt) => visitor.visit_if_stmt(&stmt),
-            Stmt::Continue(stmt) => visitor.visit_continue_stmt(&stmt),
-            Stmt::Return(stmt) => visitor.visit_return_stmt(&stmt),
-            Stmt::Break(stmt) => visitor.visit_break_stmt(&stmt),
-            Stmt::TryCatch(stmt) => visitor.visit_try_catch_stmt(&stmt),
-            Stmt::Expr(stmt) => visitor.visit_expr_stmt(&stmt),
+            Stmt::Fn(stmt) => visitor.visit_fn_stmt(stmt),
+            Stmt::Let(stmt) => visitor.visit_let_stmt(stmt),
+            Stmt::Block(stmt) => visitor.visit_block_stmt(stmt),
+            Stmt::Class(stmt) => visitor.visit_class_stmt(stmt),
+            Stmt::ClassicFor(stmt) => visitor.visit_classic_for_stmt(stmt),
+            Stmt::EnhancedFor(stmt) => visitor.visit_enhanced_for_stmt(stmt),
+            Stmt::While(stmt) => visitor.visit_while_stmt(stmt),
+            Stmt::If(stmt) => visitor.visit_if_stmt(stmt),
+            Stmt::Continue(stmt) => visitor.visit_continue_stmt(stmt),
+            Stmt::Return(stmt) => visitor.visit_return_stmt(stmt),
+            Stmt::Break(stmt) => visitor.visit_break_stmt(stmt),
+            Stmt::TryCatch(stmt) => visitor.visit_try_catch_stmt(stmt),
+            Stmt::Throw(stmt) => visitor.visit_throw_stmt(stmt),
+            Stmt::Expr(stmt) => visitor.visit_expr_stmt(stmt),
         }
     }
 }
@@ -2015,22 +2336,24 @@ impl StmtAcceptor<Chunk> for Stmt {
 impl ExprAcceptor<Chunk> for Expr {
     fn accept(&self, visitor: &mut dyn ExprVisitor<Chunk>) -> Chunk {
         match self {
-            Expr::Fn(expr) => visitor.visit_function_expr(&expr),
-            Expr::Var(expr) => visitor.visit_variable_expr(&expr),
-            Expr::List(expr) => visitor.visit_list_expr(&expr),
-            Expr::Dictionary(expr) => visitor.visit_dictionary_expr(&expr),
-            Expr::Assign(expr) => visitor.visit_assign_expr(&expr),
-            Expr::Access(expr) => visitor.visit_access_expr(&expr),
-            Expr::Binary(expr) => visitor.visit_binary_expr(&expr),
-            Expr::Call(expr) => visitor.visit_call_expr(&expr),
-            Expr::Get(expr) => visitor.visit_get_expr(&expr),
-            Expr::Set(expr) => visitor.visit_set_expr(&expr),
-            Expr::Super(expr) => visitor.visit_super_expr(&expr),
-            Expr::Grouping(expr) => visitor.visit_grouping_expr(&expr),
-            Expr::Literal(expr) => visitor.visit_literal_expr(&expr),
-            Expr::Logical(expr) => visitor.visit_logical_expr(&expr),
-            Expr::This(expr) => visitor.visit_this_expr(&expr),
-            Expr::Unary(expr) => visitor.visit_unary_expr(&expr),
+            Expr::Fn(expr) => visitor.visit_function_expr(expr),
+            Expr::Var(expr) => visitor.visit_variable_expr(expr),
+            Expr::List(expr) => visitor.visit_list_expr(expr),
+            Expr::Dictionary(expr) => visitor.visit_dictionary_expr(expr),
+            Expr::Assign(expr) => visitor.visit_assign_expr(expr),
+            Expr::Access(expr) => visitor.visit_access_expr(expr),
+            Expr::Binary(expr) => visitor.visit_binary_expr(expr),
+            Expr::Call(expr) => visitor.visit_call_expr(expr),
+            Expr::Get(expr) => visitor.visit_get_expr(expr),
+            Expr::Set(expr) => visitor.visit_set_expr(expr),
+            Expr::Super(expr) => visitor.visit_super_expr(expr),
+            Expr::Grouping(expr) => visitor.visit_grouping_expr(expr),
+            Expr::Literal(expr) => visitor.visit_literal_expr(expr),
+            Expr::FString(expr) => visitor.visit_fstring_expr(expr),
+            Expr::Logical(expr) => visitor.visit_logical_expr(expr),
+            Expr::Conditional(expr) => visitor.visit_conditional_expr(expr),
+            Expr::This(expr) => visitor.visit_this_expr(expr),
+            Expr::Unary(expr) => visitor.visit_unary_expr(expr),
             Expr::Empty => Chunk {
                 instructions: vec![],
                 result_register: 0,
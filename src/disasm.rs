@@ -0,0 +1,176 @@
+use std::fmt::Write;
+
+use crate::compiler::{Compiler, FnContext, FnPrototype, InstSrc};
+use crate::instruction::{Instruction, OpCode};
+use crate::token::TokenData;
+use crate::value::Value;
+
+/// Human readable listing of compiled code: the constants table, the top level
+/// code and every function prototype, one instruction per line.
+pub fn disassemble(compiler: &Compiler) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "constants ({}):", compiler.constants.len()).unwrap();
+    for (ix, constant) in compiler.constants.iter().enumerate() {
+        writeln!(out, "    {:<5} {}", ix, Value::from(constant).repr()).unwrap();
+    }
+
+    if let Some(main) = compiler.contexts.last() {
+        write_top_level(&mut out, compiler, "fn <main>", main);
+    }
+    for (key, module) in &compiler.modules {
+        write_top_level(&mut out, compiler, &format!("module {}", key), module);
+    }
+
+    for (ix, prototype) in compiler.prototypes.iter().enumerate() {
+        write_prototype(&mut out, compiler, ix, prototype);
+    }
+    out
+}
+
+fn write_top_level(out: &mut String, compiler: &Compiler, title: &str, context: &FnContext) {
+    let instructions: Vec<&InstSrc> = context
+        .chunks
+        .iter()
+        .flat_map(|chunk| chunk.instructions.iter())
+        .collect();
+    writeln!(
+        out,
+        "\n{} ({} instructions, {} registers)",
+        title,
+        instructions.len(),
+        context.register_count
+    )
+    .unwrap();
+    for (pc, inst) in instructions.iter().enumerate() {
+        write_instruction(out, compiler, pc, &inst.inst, &inst.src);
+    }
+}
+
+fn write_prototype(out: &mut String, compiler: &Compiler, ix: usize, prototype: &FnPrototype) {
+    let name = if prototype.name.is_empty() {
+        "<anonymous>"
+    } else {
+        &prototype.name
+    };
+    writeln!(
+        out,
+        "\nfn {} #{} ({} params, {} instructions, {} registers) {}",
+        name,
+        ix,
+        prototype.param_count,
+        prototype.instructions.len(),
+        prototype.register_count,
+        prototype.file_path
+    )
+    .unwrap();
+    if !prototype.upvalues.is_empty() {
+        writeln!(out, "  upvalues ({}):", prototype.upvalues.len()).unwrap();
+        for (ix, upvalue) in prototype.upvalues.iter().enumerate() {
+            // Captured either from a register of the enclosing function or from its upvalues
+            let source = if upvalue.is_local { "R" } else { "U" };
+            writeln!(out, "    {:<5} {}{}", ix, source, upvalue.index).unwrap();
+        }
+    }
+    for (pc, inst) in prototype.instructions.iter().enumerate() {
+        let src = prototype.instruction_data.get(pc).cloned().flatten();
+        write_instruction(out, compiler, pc, inst, &src);
+    }
+}
+
+fn write_instruction(
+    out: &mut String,
+    compiler: &Compiler,
+    pc: usize,
+    inst: &Instruction,
+    src: &Option<TokenData>,
+) {
+    let line = match src {
+        Some(tk) => format!("[{}]", tk.line),
+        None => "[-]".to_string(),
+    };
+    let opcode = format!("{:?}", inst.opcode);
+    let (operands, comment) = decode(compiler, pc, inst);
+    let text = format!("    {:<5} {:<6} {:<18} {}", pc, line, opcode, operands);
+    if comment.is_empty() {
+        writeln!(out, "{}", text.trim_end()).unwrap();
+    } else {
+        writeln!(out, "{:<48} ; {}", text, comment).unwrap();
+    }
+}
+
+fn constant(compiler: &Compiler, ix: u32) -> String {
+    match compiler.constants.get(ix as usize) {
+        Some(constant) => Value::from(constant).repr(),
+        None => "<invalid constant>".to_string(),
+    }
+}
+
+// Operands in the layout used by the opcode and a note explaining them
+fn decode(compiler: &Compiler, pc: usize, inst: &Instruction) -> (String, String) {
+    let abc = format!("{} {} {}", inst.a, inst.b, inst.c);
+    let abx = format!("{} {}", inst.a, inst.bx());
+    match inst.opcode {
+        OpCode::LoadK | OpCode::GetGlobal | OpCode::SetGlobal | OpCode::GetBuiltin => {
+            (abx, constant(compiler, inst.bx()))
+        }
+        OpCode::Closure => {
+            let name = match compiler.prototypes.get(inst.bx() as usize) {
+                Some(p) if !p.name.is_empty() => p.name.clone(),
+                Some(_) => "<anonymous>".to_string(),
+                None => "<invalid prototype>".to_string(),
+            };
+            (abx, format!("fn {} #{}", name, inst.bx()))
+        }
+        OpCode::Jmp => {
+            let target = (pc as i64) + inst.sbx() as i64;
+            (format!("{} {}", inst.a, inst.sbx()), format!("to {}", target))
+        }
+        OpCode::RegisterTryCatch => (abx, format!("catch at {}", pc + inst.bx() as usize)),
+        OpCode::Addi | OpCode::Subi | OpCode::GetIteri => (abc, format!("C = {}", inst.c)),
+        OpCode::Test => (abc, format!("if truthy(R{}) == {}", inst.b, inst.c != 0)),
+        OpCode::Call => {
+            let comment = if inst.c > 0 {
+                format!("R{} = R{}({} args)", inst.c - 1, inst.a, inst.b.saturating_sub(1))
+            } else {
+                format!("R{}({} args)", inst.a, inst.b.saturating_sub(1))
+            };
+            (abc, comment)
+        }
+        OpCode::Concat if inst.c > 0 => (
+            abc,
+            format!("R{} = R{} .. R{}", inst.a, inst.b, inst.b + inst.c - 1),
+        ),
+        OpCode::Return => {
+            let comment = if inst.b == inst.a + 2 {
+                format!("return R{}", inst.a)
+            } else {
+                "return".to_string()
+            };
+            (abc, comment)
+        }
+        _ => (abc, String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let mut runtime = Runtime::new("test.gr".to_string());
+        let source = "let a = 1\nfn f(x) {\n    return x + a\n}\nwhile a < 3 {\n    a = f(a)\n}";
+        let listing = disassemble(runtime.compile(source.to_string()).unwrap());
+        assert!(listing.contains("fn <main>"));
+        assert!(listing.contains("fn f #0 (1 params"));
+        assert!(listing.contains("SetGlobal          0 0          ; \"a\""));
+        assert!(listing.contains("Closure            1 0          ; fn f #0"));
+        assert!(listing.contains("; return R"));
+        let jumps: Vec<&str> = listing.lines().filter(|l| l.contains(" Jmp ")).collect();
+        assert_eq!(jumps.len(), 2);
+        assert!(jumps.iter().all(|l| l.contains("; to ")));
+    }
+}
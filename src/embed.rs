@@ -1,7 +1,19 @@
+use std::collections::BTreeMap;
 use std::env;
-use std::{fs::{read, write}, process::exit, ptr};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+use std::{fs::{create_dir_all, read, read_dir, write}, ptr};
+#[cfg(unix)]
+use std::fs::{set_permissions, Permissions};
 
+use serde::{Deserialize, Serialize};
+
+use crate::bytecode;
+use crate::errors::Error;
 use crate::interpreter;
+use crate::value::Value;
 
 // Add compile-time random byte generation functions
 const fn simple_compile_time_lcg(seed: u64) -> u64 {
@@ -91,39 +103,269 @@ fn find_position(haystack: &Vec<u8>, needle: &[u8; 512]) -> Option<usize> {
     None
 }
 
-pub fn embed_file(compiled_script: String, output_binary: String) {
-    let exe_path = env::current_exe().unwrap();
-    let mut exe_contents = read(exe_path).unwrap();
-    let pattern = magic_pattern();
-    if let Some(pos) = find_position(&exe_contents, pattern) {
-        exe_contents[pos+512] = 1;
-        let mut compiled_content = read(compiled_script).unwrap();
-        for i in 0..512 {
-            exe_contents.push(pattern[i]);
+// Layout of an embedded executable, integers are little endian:
+//
+//   runtime         grotsky executable with the marker flag set
+//   payload         bincode serialized Payload
+//   trailer         payload offset u64, payload length u64, checksum u64 (FNV-1a
+//                   of the payload), format version u16 and TRAILER_MAGIC
+//
+// The trailer has a fixed size, so the payload is found without scanning the file.
+const TRAILER_MAGIC: &[u8; 8] = b"GRTSKYEX";
+const TRAILER_VERSION: u16 = 1;
+const TRAILER_LEN: usize = 8 + 8 + 8 + 2 + TRAILER_MAGIC.len();
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    bytecode: Vec<u8>,
+    assets: BTreeMap<String, Vec<u8>>,
+}
+
+fn append_payload(exe_contents: &mut Vec<u8>, payload: &Payload) {
+    let data = bincode::serialize(payload).unwrap();
+    let offset = exe_contents.len() as u64;
+    exe_contents.extend_from_slice(&data);
+    exe_contents.extend_from_slice(&offset.to_le_bytes());
+    exe_contents.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    exe_contents.extend_from_slice(&bytecode::checksum(&data).to_le_bytes());
+    exe_contents.extend_from_slice(&TRAILER_VERSION.to_le_bytes());
+    exe_contents.extend_from_slice(TRAILER_MAGIC);
+}
+
+fn embedded_error(msg: String) -> Error {
+    Error::Bytecode(format!("Cannot read embedded script: {}", msg))
+}
+
+struct Trailer {
+    length: u64,
+    checksum: u64,
+}
+
+// Reads only the trailer and the payload it points to
+fn read_payload(path: &Path) -> Result<(Payload, Trailer), Error> {
+    let io_error = |err: io::Error| embedded_error(err.to_string());
+    let mut file = File::open(path).map_err(io_error)?;
+    let size = file.metadata().map_err(io_error)?.len();
+    if size < TRAILER_LEN as u64 {
+        return Err(embedded_error("the executable is truncated".to_string()));
+    }
+    let mut trailer = [0u8; TRAILER_LEN];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64))).map_err(io_error)?;
+    file.read_exact(&mut trailer).map_err(io_error)?;
+
+    let field = |start: usize| u64::from_le_bytes(trailer[start..start + 8].try_into().unwrap());
+    let (offset, length, checksum) = (field(0), field(8), field(16));
+    let version = u16::from_le_bytes([trailer[24], trailer[25]]);
+    if &trailer[26..] != TRAILER_MAGIC {
+        return Err(embedded_error(format!(
+            "no payload found, {} is not an embedded executable or it is truncated",
+            path.display()
+        )));
+    }
+    if version != TRAILER_VERSION {
+        return Err(embedded_error(format!(
+            "unsupported payload format version {}, expected {}",
+            version, TRAILER_VERSION
+        )));
+    }
+    if offset.checked_add(length) != Some(size - TRAILER_LEN as u64) {
+        return Err(embedded_error("the payload is truncated".to_string()));
+    }
+
+    let mut data = vec![0u8; length as usize];
+    file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+    file.read_exact(&mut data).map_err(io_error)?;
+    if bytecode::checksum(&data) != checksum {
+        return Err(embedded_error(
+            "checksum mismatch, the executable is corrupted".to_string(),
+        ));
+    }
+    let payload = bincode::deserialize(&data).map_err(|err| embedded_error(err.to_string()))?;
+    Ok((payload, Trailer { length, checksum }))
+}
+
+/// Description of the script embedded in an executable: sizes, checksums, the
+/// version of grotsky that compiled it, and the modules and assets bundled with it.
+pub fn info(path: &Path) -> Result<String, Error> {
+    let (payload, trailer) = read_payload(path)?;
+    let mut out = String::new();
+    writeln!(out, "payload:  {} bytes, checksum {:016x}", trailer.length, trailer.checksum).unwrap();
+
+    let (header, _) = bytecode::read_header(&payload.bytecode)?;
+    let mut options = vec![];
+    if header.flags & bytecode::FLAG_STRIPPED != 0 {
+        options.push("stripped");
+    }
+    if header.flags & bytecode::FLAG_COMPRESSED != 0 {
+        options.push("compressed");
+    }
+    writeln!(
+        out,
+        "bytecode: {} bytes, format {}, grotsky {}, checksum {:016x}{}",
+        payload.bytecode.len(),
+        header.format_version,
+        header.grotsky_version,
+        header.checksum,
+        options.iter().map(|o| format!(", {}", o)).collect::<String>()
+    )
+    .unwrap();
+
+    // Modules can only be listed when this version can decode the bytecode
+    match bytecode::decode(&payload.bytecode) {
+        Ok(compiler) => {
+            writeln!(out, "modules ({}):", compiler.modules.len()).unwrap();
+            for name in compiler.modules.keys() {
+                writeln!(out, "    {}", name).unwrap();
+            }
         }
-        exe_contents.append(&mut compiled_content);
-        write(output_binary, exe_contents).unwrap();
+        Err(err) => writeln!(out, "modules: unknown, {}", err.to_string().replace("\n\t", ": ")).unwrap(),
     }
+    writeln!(out, "assets ({}):", payload.assets.len()).unwrap();
+    for (name, content) in &payload.assets {
+        writeln!(out, "    {:<40} {} bytes", name, content.len()).unwrap();
+    }
+    Ok(out)
+}
+
+/// Writes the compiled script embedded in an executable to `output`.
+pub fn extract(path: &Path, output: &Path) -> Result<(), Error> {
+    let (payload, _) = read_payload(path)?;
+    write(output, payload.bytecode).map_err(|err| {
+        Error::Bytecode(format!("Cannot write {}: {}", output.display(), err))
+    })
 }
 
-pub fn execute_embedded() {
-    let exe_path = env::current_exe().unwrap();
-    interpreter::set_absolute_path(exe_path.clone().to_str().unwrap().to_string());
+/// Reads the files to embed as assets. Directories are walked recursively. Files
+/// are keyed by their path relative to `base`, paths outside of `base` are rejected.
+pub fn collect_assets(base: &Path, paths: &[String]) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut assets = BTreeMap::new();
+    for path in paths {
+        let name = asset_name(base, Path::new(path)).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of {}", path, base.display()),
+            )
+        })?;
+        add_asset(&mut assets, &base.join(path), &name)?;
+    }
+    Ok(assets)
+}
 
-    let exe_contents = read(exe_path).unwrap();
+fn add_asset(assets: &mut BTreeMap<String, Vec<u8>>, path: &Path, name: &str) -> io::Result<()> {
+    if path.is_dir() {
+        for entry in read_dir(path)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let entry_name = if name.is_empty() {
+                file_name
+            } else {
+                format!("{}/{}", name, file_name)
+            };
+            add_asset(assets, &entry.path(), &entry_name)?;
+        }
+    } else {
+        assets.insert(name.to_string(), read(path)?);
+    }
+    Ok(())
+}
+
+// Same form used to look the asset up: relative to base with '/' separators.
+// The path is resolved without touching the filesystem, None if it escapes base.
+fn asset_name(base: &Path, path: &Path) -> Option<String> {
+    let mut parts: Vec<String> = vec![];
+    let relative = if path.is_absolute() {
+        path.strip_prefix(base).ok()?
+    } else {
+        path
+    };
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Writes a copy of the `runtime` grotsky executable with the compiled script and
+/// the assets appended, so that it runs the script when started.
+pub fn embed_file(
+    compiled_script: &Path,
+    assets: BTreeMap<String, Vec<u8>>,
+    runtime: &Path,
+    output_binary: &Path,
+) -> io::Result<()> {
+    let bytecode = read(compiled_script)?;
+    check_bytecode(compiled_script, &bytecode)?;
+    let mut exe_contents = read(runtime)?;
     let pattern = magic_pattern();
-    let offset: usize = 512;
-    let first_match = find_position(&exe_contents, pattern).unwrap();
-    let remaining = &exe_contents[first_match+offset..].to_vec();
-    let pos = find_position(remaining, pattern).unwrap();
-    let compiled_content = &remaining[pos+offset..];
-    
-    if !interpreter::run_interpreter_from_bytecode(&compiled_content) {
-        println!("Could not read embedded script");
-        exit(1);
+    let pos = match find_position(&exe_contents, pattern) {
+        Some(pos) => pos,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a grotsky executable", runtime.display()),
+            ))
+        }
+    };
+    if exe_contents[pos+512] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} already has an embedded script", runtime.display()),
+        ));
+    }
+    exe_contents[pos+512] = 1;
+    let payload = Payload {
+        bytecode,
+        assets,
+    };
+    append_payload(&mut exe_contents, &payload);
+    if let Some(dir) = output_binary.parent() {
+        create_dir_all(dir)?;
+    }
+    write(output_binary, exe_contents)?;
+    set_executable(output_binary)
+}
+
+// Only the magic and the format are checked, the runtime may be another version
+// of grotsky than the one embedding the script
+fn check_bytecode(path: &Path, bytecode: &[u8]) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    match bytecode::read_header(bytecode) {
+        Ok((header, _)) if header.format_version != bytecode::FORMAT_VERSION => invalid(format!(
+            "{} has bytecode format version {}, expected {}. Compile the script again",
+            path.display(),
+            header.format_version,
+            bytecode::FORMAT_VERSION
+        )),
+        Ok(_) => Ok(()),
+        Err(_) => invalid(format!("{} is not a compiled grotsky script", path.display())),
     }
 }
 
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    set_permissions(path, Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+pub fn execute_embedded() -> Result<Value, Error> {
+    let exe_path = env::current_exe().map_err(|err| embedded_error(err.to_string()))?;
+    let (payload, _) = read_payload(&exe_path)?;
+    let mut runtime = interpreter::Runtime::new(exe_path.to_string_lossy().to_string());
+    runtime.set_assets(payload.assets);
+    runtime.run_interpreter_from_bytecode(&payload.bytecode)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -153,6 +395,75 @@ mod tests {
         assert_eq!(find_position(&empty, &needle), None);
     }
 
+    #[test]
+    fn test_asset_name() {
+        let base = Path::new("/app");
+        let name = |path: &str| asset_name(base, Path::new(path));
+        assert_eq!(name("templates/index.html").unwrap(), "templates/index.html");
+        assert_eq!(name("./static/").unwrap(), "static");
+        assert_eq!(name(".").unwrap(), "");
+        assert_eq!(name("/app/static/app.js").unwrap(), "static/app.js");
+        assert_eq!(name("static/../index.html").unwrap(), "index.html");
+        assert_eq!(name("../secrets.txt"), None);
+        assert_eq!(name("static/../../app/index.html"), None);
+        assert_eq!(name("/etc/passwd"), None);
+    }
+
+    #[test]
+    fn test_embed_checks_bytecode() {
+        let message = |path: &Path| {
+            let runtime = env::current_exe().unwrap();
+            let output = env::temp_dir().join("grotsky_test_check_bytecode.exe");
+            embed_file(path, BTreeMap::new(), &runtime, &output).unwrap_err().to_string()
+        };
+        let path = env::temp_dir().join("grotsky_test_check_bytecode.grc");
+        write(&path, b"io.println(1)").unwrap();
+        assert!(message(&path).contains("is not a compiled grotsky script"));
+
+        let mut old = bytecode::MAGIC.to_vec();
+        old.extend_from_slice(&(bytecode::FORMAT_VERSION - 1).to_le_bytes());
+        write(&path, &old).unwrap();
+        assert!(message(&path).contains("expected"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_payload() {
+        let message = |path: &Path| match read_payload(path) {
+            Err(Error::Bytecode(msg)) => msg,
+            _ => panic!("expected an error reading the payload"),
+        };
+        let path = env::temp_dir().join("grotsky_test_payload.exe");
+        let mut assets = BTreeMap::new();
+        assets.insert("a.txt".to_string(), b"asset".to_vec());
+        let payload = Payload {
+            bytecode: b"bytecode".to_vec(),
+            assets,
+        };
+        let mut exe = b"runtime".to_vec();
+        append_payload(&mut exe, &payload);
+
+        write(&path, &exe).unwrap();
+        let (read_back, _) = read_payload(&path).unwrap();
+        assert_eq!(read_back.bytecode, b"bytecode");
+        assert_eq!(read_back.assets["a.txt"], b"asset");
+
+        let mut corrupted = exe.clone();
+        corrupted[10] ^= 0xff;
+        write(&path, &corrupted).unwrap();
+        assert!(message(&path).contains("checksum mismatch"));
+
+        write(&path, &exe[1..]).unwrap();
+        assert!(message(&path).contains("payload is truncated"));
+
+        write(&path, &exe[..exe.len() - 1]).unwrap();
+        assert!(message(&path).contains("no payload found"));
+
+        write(&path, b"tiny").unwrap();
+        assert!(message(&path).contains("truncated"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_is_embedded_default() {
         assert_eq!(is_embedded(), false);
@@ -1,8 +1,94 @@
-use crate::value::Value;
+use crate::state::InterpreterError;
+use crate::token::TokenData;
+use crate::value::{
+    ListValue, MutValue, NativeBaggage, NativeValue, NumberValue, StringValue, Value,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Category of a runtime error, exposed to scripts as `err.kind`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Error,
+    Name,
+    Type,
+    Value,
+    Property,
+    Argument,
+    Index,
+    Recursion,
+    IO,
+    Import,
+    Syntax,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Error => "Error",
+            ErrorKind::Name => "NameError",
+            ErrorKind::Type => "TypeError",
+            ErrorKind::Value => "ValueError",
+            ErrorKind::Property => "PropertyError",
+            ErrorKind::Argument => "ArgumentError",
+            ErrorKind::Index => "IndexError",
+            ErrorKind::Recursion => "RecursionError",
+            ErrorKind::IO => "IOError",
+            ErrorKind::Import => "ImportError",
+            ErrorKind::Syntax => "SyntaxError",
+        }
+    }
+}
+
+/// Position in the source code where an error was raised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: i32,
+    pub column: i32,
+    // Line of the source code that was compiled, unknown when running bytecode
+    pub source_line: Option<String>,
+}
+
+/// Frame of a stack trace, with the line that frame was executing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub file: String,
+    pub function: String,
+    pub line: Option<i32>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(
+                f,
+                "File \"{}\", line {}, in {}",
+                self.file, line, self.function
+            ),
+            None => write!(f, "File \"{}\", in {}", self.file, self.function),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct RuntimeErr {
-    pub msg: &'static str,
+    pub msg: Cow<'static, str>,
+    pub kind: ErrorKind,
+    pub cause: Option<Box<RuntimeErr>>,
+
+    // Filled in by the VM when the error is raised. Boxed along with the thrown
+    // value to keep results that can fail small
+    pub location: Option<Box<Location>>,
+    pub token: Option<Box<TokenData>>,
+    // Outermost frame first
+    pub stack: Vec<TraceFrame>,
+
+    // Value raised by a throw statement, handed back as is to catch blocks
+    pub value: Option<Box<Value>>,
 
     // Signaling of events that should be handled by the VM
     pub signal: Option<Value>,
@@ -12,59 +98,376 @@ const _SINGAL_MSG: &'static str = "";
 
 impl RuntimeErr {
     pub const fn new(msg: &'static str) -> RuntimeErr {
+        RuntimeErr::new_kind(ErrorKind::Error, msg)
+    }
+
+    pub const fn new_kind(kind: ErrorKind, msg: &'static str) -> RuntimeErr {
         RuntimeErr {
-            msg: msg,
+            msg: Cow::Borrowed(msg),
+            kind,
+            cause: None,
+            location: None,
+            token: None,
+            stack: Vec::new(),
+            value: None,
             signal: None,
         }
     }
 
+    /// Error with a message built at runtime, e.g. including a file name.
+    pub fn message(kind: ErrorKind, msg: String) -> RuntimeErr {
+        RuntimeErr {
+            msg: Cow::Owned(msg),
+            ..RuntimeErr::new_kind(kind, _SINGAL_MSG)
+        }
+    }
+
     pub fn new_signal(v: Value) -> RuntimeErr {
         RuntimeErr {
-            msg: _SINGAL_MSG,
             signal: Some(v),
+            ..RuntimeErr::new(_SINGAL_MSG)
         }
     }
+
+    /// Error raised by `throw`. Rethrowing a caught error keeps its original details.
+    pub fn thrown(v: Value) -> RuntimeErr {
+        let msg = match &v {
+            Value::Native(n) => match n.error() {
+                Some(err) => return err,
+                None => v.string(),
+            },
+            // Instances of user error classes are described by their class and message
+            Value::Object(o) => {
+                let obj = o.0.borrow();
+                let class_name = obj.class.0.borrow().name.clone();
+                match obj.fields.get("message") {
+                    Some(message) => format!("{}: {}", class_name, message.string()),
+                    None => class_name,
+                }
+            }
+            _ => v.string(),
+        };
+        RuntimeErr {
+            msg: Cow::Owned(msg),
+            value: Some(Box::new(v)),
+            ..RuntimeErr::new(_SINGAL_MSG)
+        }
+    }
+
+    /// Describes the error like rustc does, pointing at the source code.
+    pub fn render(&self) -> String {
+        let uncaught = if self.value.is_some() { "Uncaught " } else { "" };
+        let mut out = match self.kind {
+            ErrorKind::Error => format!("error: {}{}\n", uncaught, self.msg),
+            kind => format!("error[{}]: {}{}\n", kind.name(), uncaught, self.msg),
+        };
+        if let Some(location) = &self.location {
+            let width = self.token.as_ref().map_or(1, |tk| tk.lexeme.chars().count());
+            out.push_str(&source_snippet(location, width));
+        }
+        out
+    }
+
+    /// Python style traceback, innermost call last.
+    pub fn traceback(&self) -> String {
+        let mut traceback = String::from("Traceback (most recent call last):\n");
+        for frame in &self.stack {
+            traceback.push_str(&format!("  {}\n", frame));
+        }
+        traceback
+    }
+
+    pub fn caused_by(mut self, cause: RuntimeErr) -> RuntimeErr {
+        self.cause = Some(Box::new(cause));
+        self
+    }
+
+    /// Value handed to catch blocks: the thrown value, or an object with
+    /// `message`, `kind`, `file`, `line`, `stack` and `cause`.
+    pub fn to_value(&self) -> Value {
+        match &self.value {
+            Some(v) => *v.clone(),
+            None => self.to_object(),
+        }
+    }
+
+    /// Object describing the error, also for thrown values. Throwing it again
+    /// raises this same error.
+    pub fn to_object(&self) -> Value {
+        let string = |s: &str| Value::String(MutValue::new(StringValue::new(s.to_string())));
+        let mut props = HashMap::new();
+        props.insert("message".to_string(), string(&self.msg));
+        props.insert("kind".to_string(), string(self.kind.name()));
+        let (file, line) = match &self.location {
+            Some(l) => (
+                string(&l.file),
+                Value::Number(NumberValue { n: l.line as f64 }),
+            ),
+            None => (Value::Nil, Value::Nil),
+        };
+        props.insert("file".to_string(), file);
+        props.insert("line".to_string(), line);
+        props.insert(
+            "stack".to_string(),
+            Value::List(MutValue::new(ListValue {
+                elements: self.stack.iter().map(|s| string(&s.to_string())).collect(),
+            })),
+        );
+        props.insert(
+            "cause".to_string(),
+            self.cause.as_ref().map_or(Value::Nil, |c| c.to_value()),
+        );
+        Value::Native(Rc::new(NativeValue {
+            props,
+            callable: None,
+            bind: false,
+            baggage: Some(Rc::new(RefCell::new(NativeBaggage::Error(self.clone())))),
+        }))
+    }
 }
 
-pub const ERR_UNDEFINED_VAR: RuntimeErr = RuntimeErr::new("Undefined variable");
-pub const ERR_GLOBAL_ALREADY_DEFINED: RuntimeErr = RuntimeErr::new("Global already defined");
+pub const ERR_UNDEFINED_VAR: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Name, "Undefined variable");
+pub const ERR_GLOBAL_ALREADY_DEFINED: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Name, "Global already defined");
 pub const ERR_ONLY_NUMBERS: RuntimeErr =
-    RuntimeErr::new("The operation is only defined for numbers");
-pub const ERR_UNDEFINED_OP: RuntimeErr = RuntimeErr::new("Undefined operation");
-pub const ERR_EXPECTED_STEP: RuntimeErr = RuntimeErr::new("Expected step of the slice");
-pub const ERR_EXPECTED_KEY: RuntimeErr = RuntimeErr::new("Expected key for accessing dictionary");
-pub const ERR_INVALID_ACCESS: RuntimeErr = RuntimeErr::new("The object is not subscriptable");
-pub const ERR_ONLY_FUNCTION: RuntimeErr = RuntimeErr::new("Can only call functions");
-pub const ERR_INVALID_NUMBER_ARGUMENTS: RuntimeErr = RuntimeErr::new("Invalid number of arguments");
-pub const ERR_EXPECTED_COLLECTION: RuntimeErr = RuntimeErr::new("Collection expected");
-pub const ERR_EXPECTED_OBJECT: RuntimeErr = RuntimeErr::new("Object expected");
+    RuntimeErr::new_kind(ErrorKind::Type, "The operation is only defined for numbers");
+pub const ERR_UNDEFINED_OP: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "Undefined operation");
+pub const ERR_EXPECTED_STEP: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Value, "Expected step of the slice");
+pub const ERR_EXPECTED_KEY: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "Expected key for accessing dictionary");
+pub const ERR_INVALID_ACCESS: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "The object is not subscriptable");
+pub const ERR_ONLY_FUNCTION: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "Can only call functions");
+pub const ERR_INVALID_NUMBER_ARGUMENTS: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Argument, "Invalid number of arguments");
+pub const ERR_EXPECTED_COLLECTION: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "Collection expected");
+pub const ERR_EXPECTED_OBJECT: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "Object expected");
 pub const ERR_EXPECTED_IDENTIFIERS_DICT: RuntimeErr =
-    RuntimeErr::new("Expected 1 or 2 identifiers for dict");
-pub const ERR_CANNOT_UNPACK: RuntimeErr = RuntimeErr::new("Cannot unpack value");
+    RuntimeErr::new_kind(ErrorKind::Value, "Expected 1 or 2 identifiers for dict");
+pub const ERR_CANNOT_UNPACK: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Value, "Cannot unpack value");
 pub const ERR_WRONG_NUMBER_OF_VALUES: RuntimeErr =
-    RuntimeErr::new("Wrong number of values to unpack");
-pub const ERR_METHOD_NOT_FOUND: RuntimeErr = RuntimeErr::new("Method not found");
-pub const ERR_UNDEFINED_PROP: RuntimeErr = RuntimeErr::new("Undefined property");
-pub const ERR_READ_ONLY: RuntimeErr =
-    RuntimeErr::new("Trying to set a property on a Read-Only object");
+    RuntimeErr::new_kind(ErrorKind::Value, "Wrong number of values to unpack");
+pub const ERR_METHOD_NOT_FOUND: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Property, "Method not found");
+pub const ERR_UNDEFINED_PROP: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Property, "Undefined property");
+pub const ERR_READ_ONLY: RuntimeErr = RuntimeErr::new_kind(
+    ErrorKind::Property,
+    "Trying to set a property on a Read-Only object",
+);
 pub const ERR_UNDEFINED_OPERATOR: RuntimeErr =
-    RuntimeErr::new("Undefined operator for this object");
+    RuntimeErr::new_kind(ErrorKind::Type, "Undefined operator for this object");
 pub const ERR_EXPECTED_NUMBER: RuntimeErr =
-    RuntimeErr::new("A number was expected at this position");
-pub const ERR_EXPECTED_CLASS: RuntimeErr = RuntimeErr::new("A class was expected at this position");
+    RuntimeErr::new_kind(ErrorKind::Type, "A number was expected at this position");
+pub const ERR_EXPECTED_CLASS: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "A class was expected at this position");
 pub const ERR_EXPECTED_STRING: RuntimeErr =
-    RuntimeErr::new("A string was expected at this position");
+    RuntimeErr::new_kind(ErrorKind::Type, "A string was expected at this position");
 pub const ERR_EXPECTED_INDEX: RuntimeErr =
-    RuntimeErr::new("An index was expected at this position");
+    RuntimeErr::new_kind(ErrorKind::Type, "An index was expected at this position");
 pub const ERR_EXPECTED_FUNCTION: RuntimeErr =
-    RuntimeErr::new("A function was expected at this position");
-pub const ERR_EXPECTED_SUPERCLASS: RuntimeErr =
-    RuntimeErr::new("Keyword 'super' is only valid inside an object");
-pub const ERR_EXPECTED_DICT: RuntimeErr =
-    RuntimeErr::new("A dictionary was expected at this position");
-pub const ERR_EXPECTED_LIST: RuntimeErr = RuntimeErr::new("A list was expected at this position");
-pub const ERR_MAX_RECURSION: RuntimeErr = RuntimeErr::new("Max recursion depth exceeded");
-pub const ERR_LIST_EMPTY: RuntimeErr = RuntimeErr::new("List is empty");
+    RuntimeErr::new_kind(ErrorKind::Type, "A function was expected at this position");
+pub const ERR_EXPECTED_SUPERCLASS: RuntimeErr = RuntimeErr::new_kind(
+    ErrorKind::Name,
+    "Keyword 'super' is only valid inside an object",
+);
+pub const ERR_EXPECTED_DICT: RuntimeErr = RuntimeErr::new_kind(
+    ErrorKind::Type,
+    "A dictionary was expected at this position",
+);
+pub const ERR_EXPECTED_LIST: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Type, "A list was expected at this position");
+pub const ERR_MAX_RECURSION: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Recursion, "Max recursion depth exceeded");
+pub const ERR_LIST_EMPTY: RuntimeErr = RuntimeErr::new_kind(ErrorKind::Index, "List is empty");
+pub const ERR_IMPORT_FAILED: RuntimeErr =
+    RuntimeErr::new_kind(ErrorKind::Import, "Cannot import module");
+
+/// Error found while compiling, points to the offending token when available.
+#[derive(Debug, Clone)]
+pub struct CompileErr {
+    pub err: RuntimeErr,
+    pub token: Option<TokenData>,
+}
+
+/// Runtime error that was not handled by a catch block.
+#[derive(Debug, Clone)]
+pub struct Exception {
+    pub err: RuntimeErr,
+}
+
+/// Everything that can go wrong when running a script through a `Runtime`.
+#[derive(Debug, Clone)]
+pub enum Error {
+    Syntax(Vec<InterpreterError>),
+    Compile(Vec<CompileErr>),
+    Runtime(Exception),
+    // Input is not bytecode
+    InvalidBytecode,
+    // Bytecode from another version of grotsky, or corrupted
+    Bytecode(String),
+}
+
+// Exit codes used by the command line when a script fails
+pub const EXIT_INVALID_BYTECODE: i32 = 1;
+pub const EXIT_SYNTAX_ERROR: i32 = 2;
+pub const EXIT_COMPILE_ERROR: i32 = 3;
+pub const EXIT_RUNTIME_ERROR: i32 = 4;
+
+/// Line of the source code, counting from 1, shown when reporting an error.
+pub fn source_line(source: &str, line: i32) -> Option<String> {
+    if line < 1 {
+        return None;
+    }
+    source.lines().nth((line - 1) as usize).map(String::from)
+}
+
+// Location of a diagnostic followed by the source line, when it is known, with
+// the offending part underlined. Column 0 means that only the line is known.
+fn source_snippet(location: &Location, width: usize) -> String {
+    let line_number = location.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    let mut out = if location.column > 0 {
+        format!(
+            "{}--> {}:{}:{}\n",
+            gutter, location.file, location.line, location.column
+        )
+    } else {
+        format!("{}--> {}:{}\n", gutter, location.file, location.line)
+    };
+    if let Some(source_line) = &location.source_line {
+        // Keep tabs so that the carets line up with the source
+        let indent: String = source_line
+            .chars()
+            .take((location.column - 1).max(0) as usize)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_number, source_line));
+        if location.column > 0 {
+            out.push_str(&format!("{} | {}{}\n", gutter, indent, "^".repeat(width.max(1))));
+        }
+    }
+    out
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Syntax(_) => EXIT_SYNTAX_ERROR,
+            Error::Compile(_) => EXIT_COMPILE_ERROR,
+            Error::Runtime(_) => EXIT_RUNTIME_ERROR,
+            Error::InvalidBytecode | Error::Bytecode(_) => EXIT_INVALID_BYTECODE,
+        }
+    }
+
+    /// Describes the error like rustc does, pointing at the source code.
+    pub fn render(&self) -> String {
+        match self {
+            Error::Syntax(errors) => errors
+                .iter()
+                .map(|err| RuntimeErr::from(err).render())
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Error::Compile(errors) => errors
+                .iter()
+                .map(|err| err.err.render())
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Error::Runtime(exc) => exc.err.render(),
+            Error::InvalidBytecode => "error: Invalid bytecode\n".to_string(),
+            Error::Bytecode(msg) => format!("error: {}\n", msg),
+        }
+    }
+
+    /// First error as a runtime error, for reporting it as the cause of another one.
+    pub fn to_runtime_err(&self) -> RuntimeErr {
+        match self {
+            Error::Syntax(errors) => errors
+                .first()
+                .map(RuntimeErr::from)
+                .unwrap_or(RuntimeErr::new_kind(ErrorKind::Syntax, "Invalid syntax")),
+            Error::Compile(errors) => errors
+                .first()
+                .map(|err| err.err.clone())
+                .unwrap_or(RuntimeErr::new("Compilation failed")),
+            Error::Runtime(exc) => exc.err.clone(),
+            Error::InvalidBytecode => RuntimeErr::new("Invalid bytecode"),
+            Error::Bytecode(msg) => RuntimeErr::message(ErrorKind::Error, msg.clone()),
+        }
+    }
+}
+
+impl From<&InterpreterError> for RuntimeErr {
+    fn from(err: &InterpreterError) -> Self {
+        RuntimeErr {
+            location: Some(Box::new(Location {
+                file: err.file.clone(),
+                line: err.line,
+                column: err.column,
+                source_line: err.source_line.clone(),
+            })),
+            ..RuntimeErr::message(ErrorKind::Syntax, err.message.clone())
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(errors) => {
+                for err in errors {
+                    write!(f, "Error on line {}\n\t{}\n", err.line, err.message)?;
+                }
+                Ok(())
+            }
+            Error::Compile(errors) => {
+                for err in errors {
+                    match &err.token {
+                        Some(tk) => write!(
+                            f,
+                            "Compilation Error on line {}\n\t{}: {}\n",
+                            tk.line, err.err.msg, tk.lexeme
+                        )?,
+                        None => write!(f, "Compilation Error\n\t{}\n", err.err.msg)?,
+                    }
+                }
+                Ok(())
+            }
+            Error::Runtime(exc) if exc.err.value.is_some() => match &exc.err.token {
+                Some(tk) => write!(
+                    f,
+                    "Runtime Error on line {}\n\tUncaught {}\n",
+                    tk.line, exc.err.msg
+                ),
+                None => write!(f, "Runtime Error\n\tUncaught {}\n", exc.err.msg),
+            },
+            Error::Runtime(exc) => match &exc.err.token {
+                // Stripped bytecode only knows the line
+                Some(tk) if tk.lexeme.is_empty() => write!(
+                    f,
+                    "Runtime Error on line {}\n\t{}\n",
+                    tk.line, exc.err.msg
+                ),
+                Some(tk) => write!(
+                    f,
+                    "Runtime Error on line {}\n\t{}: {}\n",
+                    tk.line, exc.err.msg, tk.lexeme
+                ),
+                None => write!(f, "Runtime Error\n\t{}\n", exc.err.msg),
+            },
+            Error::InvalidBytecode => writeln!(f, "Invalid bytecode"),
+            Error::Bytecode(msg) => writeln!(f, "Invalid bytecode\n\t{}", msg),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -78,6 +481,40 @@ mod tests {
         assert!(err.signal.is_none());
     }
 
+    #[test]
+    fn test_exit_codes() {
+        let codes = vec![
+            Error::Syntax(vec![]).exit_code(),
+            Error::Compile(vec![]).exit_code(),
+            Error::InvalidBytecode.exit_code(),
+            Error::Bytecode(String::new()).exit_code(),
+        ];
+        assert_eq!(
+            codes,
+            vec![
+                EXIT_SYNTAX_ERROR,
+                EXIT_COMPILE_ERROR,
+                EXIT_INVALID_BYTECODE,
+                EXIT_INVALID_BYTECODE
+            ]
+        );
+        assert!(codes.iter().all(|c| *c != 0 && *c != EXIT_RUNTIME_ERROR));
+    }
+
+    #[test]
+    fn test_to_runtime_err_without_errors() {
+        assert_eq!(Error::Syntax(vec![]).to_runtime_err().kind, ErrorKind::Syntax);
+        assert_eq!(Error::Compile(vec![]).to_runtime_err().msg, "Compilation failed");
+    }
+
+    #[test]
+    fn test_thrown() {
+        let err = RuntimeErr::thrown(Value::Number(NumberValue { n: 3.0 }));
+        assert_eq!(err.msg, "3");
+        assert_eq!(err.value.as_ref().unwrap().string(), "3");
+        assert!(err.render().contains("Uncaught 3"));
+    }
+
     #[test]
     fn test_new_signal() {
         let val = Value::Number(NumberValue { n: 1.0 });
@@ -85,4 +522,27 @@ mod tests {
         assert_eq!(err.msg, "");
         assert!(err.signal.is_some());
     }
+
+    #[test]
+    fn test_render() {
+        let mut err = ERR_ONLY_NUMBERS;
+        err.location = Some(Box::new(Location {
+            file: "script.gr".to_string(),
+            line: 2,
+            column: 11,
+            source_line: source_line("let a = 1\nlet b = a + nil\n", 2),
+        }));
+        assert_eq!(
+            err.render(),
+            "error[TypeError]: The operation is only defined for numbers\n \
+             --> script.gr:2:11\n  |\n2 | let b = a + nil\n  |           ^\n"
+        );
+
+        // Without the source only the location is shown
+        err.location.as_mut().unwrap().source_line = None;
+        assert_eq!(
+            err.render(),
+            "error[TypeError]: The operation is only defined for numbers\n --> script.gr:2:11\n"
+        );
+    }
 }
@@ -29,6 +29,8 @@ pub struct AssignExpr {
     pub name: TokenData,
     pub value: Box<Expr>,
     pub access: Option<AccessExpr>,
+    // Operator token of compound assignments like +=
+    pub operator: Option<TokenData>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -68,6 +70,8 @@ pub struct SetExpr {
     pub name: TokenData,
     pub value: Box<Expr>,
     pub access: Option<AccessExpr>,
+    // Operator token of compound assignments like +=
+    pub operator: Option<TokenData>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -86,6 +90,22 @@ pub struct LiteralExpr {
     pub value: Literal,
 }
 
+// Parts are joined as strings, literal text and expressions alike
+#[derive(Debug, PartialEq, Clone)]
+pub struct FStringExpr {
+    pub parts: Vec<Expr>,
+    pub token: TokenData,
+}
+
+// condition ? then_branch : else_branch
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConditionalExpr {
+    pub condition: Box<Expr>,
+    pub question: TokenData,
+    pub then_branch: Box<Expr>,
+    pub else_branch: Box<Expr>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct LogicalExpr {
     pub left: Box<Expr>,
@@ -119,7 +139,9 @@ pub enum Expr {
     Super(SuperExpr),
     Grouping(GroupingExpr),
     Literal(LiteralExpr),
+    FString(FStringExpr),
     Logical(LogicalExpr),
+    Conditional(ConditionalExpr),
     This(ThisExpr),
     Unary(UnaryExpr),
     Empty,
@@ -148,7 +170,9 @@ pub trait ExprVisitor<T> {
     fn visit_super_expr(&mut self, expr: &SuperExpr) -> T;
     fn visit_grouping_expr(&mut self, expr: &GroupingExpr) -> T;
     fn visit_literal_expr(&mut self, expr: &LiteralExpr) -> T;
+    fn visit_fstring_expr(&mut self, expr: &FStringExpr) -> T;
     fn visit_logical_expr(&mut self, expr: &LogicalExpr) -> T;
+    fn visit_conditional_expr(&mut self, expr: &ConditionalExpr) -> T;
     fn visit_this_expr(&mut self, expr: &ThisExpr) -> T;
     fn visit_unary_expr(&mut self, expr: &UnaryExpr) -> T;
 }
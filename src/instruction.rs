@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 #[repr(u8)]
 pub enum OpCode {
     Move,
@@ -54,34 +54,60 @@ pub enum OpCode {
     RegisterTryCatch,
     DeregisterTryCatch,
     GetExcept,
+    Throw,
+    Concat,
+    // Only found in Code, stands for an instruction kept in its wide form
+    Wide,
 }
 
 // To indicate if the JMP is a continue or break inside
 // a loop we asssign to the unused R(A) register one of
 // the following values
-pub const JMP_CONTINUE: u8 = 1;
-pub const JMP_BREAK: u8 = 2;
+pub const JMP_CONTINUE: u32 = 1;
+pub const JMP_BREAK: u32 = 2;
+
+// Operands are 32 bits wide so that functions can use any number of registers,
+// constants and prototypes, and jump over any amount of code. Bx spans B and C,
+// and sBx is stored as Bx in excess-K, so that the common case fits in a byte per
+// operand when the VM runs it, see Code.
+pub const SBX_BIAS: i32 = 0x7fff;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instruction {
     pub opcode: OpCode,
-    pub a: u8,
-    pub b: u8,
-    pub c: u8,
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
 }
 
 impl Instruction {
-    pub fn bx(&self) -> u16 {
-        let b = self.b as u16;
-        let c = self.c as u16;
-        return b << 8 | c;
+    pub fn abx(opcode: OpCode, a: u32, bx: u32) -> Instruction {
+        let mut inst = Instruction { opcode, a, b: 0, c: 0 };
+        inst.set_bx(bx);
+        inst
+    }
+
+    pub fn asbx(opcode: OpCode, a: u32, sbx: i32) -> Instruction {
+        let mut inst = Instruction { opcode, a, b: 0, c: 0 };
+        inst.set_sbx(sbx);
+        inst
+    }
+
+    pub fn bx(&self) -> u32 {
+        self.b << 8 | self.c
+    }
+
+    pub fn set_bx(&mut self, bx: u32) {
+        self.b = bx >> 8;
+        self.c = bx & 0xff;
+    }
+
+    pub fn sbx(&self) -> i32 {
+        (self.bx() as i32).wrapping_sub(SBX_BIAS)
     }
 
-    pub fn sbx(&self) -> i16 {
-        let b = self.b as u16;
-        let c = self.c as u16;
-        let result = (b << 8 | c) as i16;
-        return result;
+    pub fn set_sbx(&mut self, sbx: i32) {
+        self.set_bx(sbx.wrapping_add(SBX_BIAS) as u32);
     }
 
     pub fn imm(&self) -> f64 {
@@ -96,3 +122,155 @@ impl Instruction {
         self.opcode == OpCode::Jmp && self.a == JMP_BREAK
     }
 }
+
+// Instruction as stored for the VM, with each operand in a byte
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    opcode: OpCode,
+    a: u8,
+    b: u8,
+    c: u8,
+}
+
+/// Instructions of a function as the VM runs them. Almost every instruction fits in
+/// the narrow form of 4 bytes. The ones with a wider operand are kept aside and
+/// replaced by a Wide instruction holding their index in A, B and C, so that the
+/// position of every instruction, and then every jump, stays the same.
+#[derive(Debug, Default)]
+pub struct Code {
+    ops: Vec<Op>,
+    wide: Vec<Instruction>,
+}
+
+impl FromIterator<Instruction> for Code {
+    fn from_iter<I: IntoIterator<Item = Instruction>>(instructions: I) -> Code {
+        let mut code = Code::default();
+        for inst in instructions {
+            let narrow = (u8::try_from(inst.a), u8::try_from(inst.b), u8::try_from(inst.c));
+            let op = match narrow {
+                (Ok(a), Ok(b), Ok(c)) if inst.opcode != OpCode::Wide => Op {
+                    opcode: inst.opcode,
+                    a,
+                    b,
+                    c,
+                },
+                _ => {
+                    let ix = code.wide.len();
+                    if ix >= 1 << 24 {
+                        panic!("Too many wide instructions");
+                    }
+                    code.wide.push(inst);
+                    Op {
+                        opcode: OpCode::Wide,
+                        a: (ix >> 16) as u8,
+                        b: (ix >> 8) as u8,
+                        c: ix as u8,
+                    }
+                }
+            };
+            code.ops.push(op);
+        }
+        code
+    }
+}
+
+impl Code {
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    #[inline(always)]
+    pub fn get(&self, pc: usize) -> Instruction {
+        let op = self.ops[pc];
+        if op.opcode == OpCode::Wide {
+            return self.get_wide(op);
+        }
+        Instruction {
+            opcode: op.opcode,
+            a: op.a as u32,
+            b: op.b as u32,
+            c: op.c as u32,
+        }
+    }
+
+    #[cold]
+    fn get_wide(&self, op: Op) -> Instruction {
+        let ix = (op.a as usize) << 16 | (op.b as usize) << 8 | op.c as usize;
+        self.wide[ix].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Runtime;
+    use crate::value::Value;
+
+    #[test]
+    fn test_code() {
+        let abc = |a, b, c| Instruction { opcode: OpCode::Move, a, b, c };
+        let instructions = vec![
+            abc(255, 255, 255),
+            abc(256, 0, 0),
+            abc(0, 0, 256),
+            Instruction::abx(OpCode::LoadK, 0, 0xffff),
+            Instruction::abx(OpCode::LoadK, 0, 0x10000),
+            Instruction::asbx(OpCode::Jmp, 0, -SBX_BIAS),
+            Instruction::asbx(OpCode::Jmp, 0, SBX_BIAS + 1),
+            Instruction::asbx(OpCode::Jmp, 0, -SBX_BIAS - 1),
+            Instruction::asbx(OpCode::Jmp, 0, SBX_BIAS + 2),
+        ];
+        let code: Code = instructions.iter().cloned().collect();
+        assert_eq!(code.len(), instructions.len());
+        assert_eq!(code.wide.len(), 5);
+        for (pc, inst) in instructions.iter().enumerate() {
+            let decoded = code.get(pc);
+            assert_eq!(decoded.opcode, inst.opcode);
+            assert_eq!((decoded.a, decoded.b, decoded.c), (inst.a, inst.b, inst.c));
+        }
+        assert_eq!(code.get(4).bx(), 0x10000);
+        assert_eq!(code.get(5).sbx(), -SBX_BIAS);
+        assert_eq!(code.get(7).sbx(), -SBX_BIAS - 1);
+        assert_eq!(code.get(8).sbx(), SBX_BIAS + 2);
+    }
+
+    #[test]
+    fn test_wide_operands() {
+        // More registers than fit in a byte. Identifiers can't have digits, so
+        // local i is named after the digits of i spelled as capital letters
+        let name = |i: usize| i.to_string().bytes().map(|d| (d - b'0' + b'A') as char).collect::<String>();
+        let locals: Vec<String> = (0..300).map(|i| format!("    let {} = {}", name(i), i)).collect();
+        let many_registers = format!(
+            "fn f() {{\n{}\n    return {} + {}\n}}\nreturn f()",
+            locals.join("\n"),
+            name(0),
+            name(299)
+        );
+        // More constants than fit in 16 bits
+        let numbers: Vec<String> = (0..70000).map(|i| i.to_string()).collect();
+        let many_constants = format!("let l = [{}]\nreturn l[69999]", numbers.join(", "));
+        // Jumps longer than 16 bits, forward over the body and back to the condition
+        let body = "    n = n + 1\n".repeat(12000);
+        let long_jumps = format!(
+            "let n = 0\nlet i = 0\nwhile i < 2 {{\n{}    i = i + 1\n}}\nif n > 0 {{\n{}}} else {{\n    n = 0\n}}\nreturn n",
+            body, body
+        );
+        for (source, expected) in [
+            (many_registers, 299.0),
+            (many_constants, 69999.0),
+            (long_jumps, 36000.0),
+        ] {
+            let mut runtime = Runtime::new("test.gr".to_string());
+            let bytecode = runtime.compile_to_bytecode(source.clone()).unwrap();
+            let mut runtime = Runtime::new("test.gr".to_string());
+            match runtime.run_interpreter_from_bytecode(&bytecode) {
+                Ok(Value::Number(n)) => assert_eq!(n.n, expected),
+                other => panic!("Expected a number, got {:?}", other),
+            }
+        }
+    }
+}
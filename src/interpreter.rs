@@ -1,280 +1,395 @@
-use crate::vm::{StackEntry, VMFnPrototype};
-use crate::{compiler, embed, lexer, native, parser, state, stmt, value, vm};
-use std::collections::{HashMap, HashSet};
-use std::panic;
+use crate::errors::{Error, RuntimeErr, ERR_UNDEFINED_VAR};
+use crate::instruction::Code;
+use crate::value::{NativeValue, Value};
+use crate::vm::{Record, StackEntry, VMFnPrototype, VM};
+use crate::{bundle, bytecode, compiler, embed, expr, lexer, native, parser, state, stmt, verifier};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::Path;
 use std::rc::Rc;
 
-static mut ABSOLUTE_PATH: &'static str = "";
+/// Independent Grotsky interpreter. Owns its VM, and the compiler that feeds it,
+/// so that many of them can coexist in the same process.
+pub struct Runtime {
+    pub vm: VM,
+}
 
-static mut GLOBAL_INTERPRETER: Option<Interpreter> = None;
+impl Runtime {
+    pub fn new(absolute_path: String) -> Runtime {
+        let mut runtime = Runtime {
+            vm: VM {
+                instructions: Rc::new(Code::default()),
+                instructions_data: Rc::new(vec![]),
+                prototypes: Rc::new(vec![]),
+                constants: vec![],
+                globals: HashMap::new(),
+                builtins: HashMap::new(),
+                frames: vec![],
+                activation_records: Vec::with_capacity(20480),
+                catch_exceptions: vec![],
+                native_call_pc: None,
+                compiler: compiler::Compiler::new(absolute_path),
+            },
+        };
+        runtime.set_builtin("io", Value::Native(native::IO::build().into()));
+        runtime.set_builtin("strings", Value::Native(native::Strings::build().into()));
+        runtime.set_builtin("type", Value::Native(native::Type::build().into()));
+        runtime.set_builtin("env", Value::Native(native::Env::build().into()));
+        runtime.set_builtin("import", Value::Native(native::Import::build().into()));
+        runtime.set_builtin("net", Value::Native(native::Net::build().into()));
+        runtime.set_builtin("re", Value::Native(native::Re::build().into()));
+        runtime.set_builtin(
+            "process",
+            Value::Native(native::Process::build(embed::is_embedded()).into()),
+        );
+        runtime.set_builtin("lists", Value::Native(native::Lists::build().into()));
+        runtime.set_assets(BTreeMap::new());
+        runtime
+    }
 
-struct Interpreter {
-    vm: vm::VM,
-    compiler: compiler::Compiler,
-}
+    /// Files readable through the `assets` builtin, keyed by relative path with '/' separators.
+    pub fn set_assets(&mut self, files: BTreeMap<String, Vec<u8>>) {
+        self.set_builtin("assets", Value::Native(native::Assets::build(files).into()));
+    }
 
-pub fn get_absolute_path() -> String {
-    unsafe { (*std::ptr::addr_of!(ABSOLUTE_PATH)).to_string() }
-}
+    /// Makes `value` available to scripts under `name`, replacing any builtin with the same name.
+    /// Only affects code compiled afterwards.
+    pub fn set_builtin(&mut self, name: &str, value: Value) {
+        self.vm.compiler.builtins.insert(name.to_string());
+        self.vm.builtins.insert(name.to_string(), value);
+    }
 
-pub fn set_absolute_path(path: String) {
-    unsafe {
-        ABSOLUTE_PATH = Box::leak(path.into_boxed_str());
+    pub fn remove_builtin(&mut self, name: &str) -> Option<Value> {
+        self.vm.compiler.builtins.remove(name);
+        self.vm.builtins.remove(name)
     }
-}
 
-fn get_global_interpreter() -> &'static mut Interpreter {
-    setup_global_interpreter();
-    let gi = unsafe { &mut *std::ptr::addr_of_mut!(GLOBAL_INTERPRETER) };
-    match gi {
-        Some(g) => g,
-        None => panic!("No global interpreter"),
+    /// Registers a Rust function or closure as a global function.
+    pub fn register_function<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut VM, Vec<Value>) -> Result<Value, RuntimeErr> + 'static,
+    {
+        self.set_builtin(name, Value::Native(Rc::new(NativeValue::function(f))));
     }
-}
 
-fn setup_global_interpreter() {
-    if unsafe { (*std::ptr::addr_of!(GLOBAL_INTERPRETER)).is_none() } {
-        let compiler = compiler::Compiler {
-            constants: vec![],
-            contexts: vec![],
-            prototypes: vec![],
-            globals: HashSet::new(),
-        };
-        let mut my_vm = vm::VM {
-            instructions: Rc::new(vec![]),
-            instructions_data: Rc::new(vec![]),
-            prototypes: Rc::new(vec![]),
-            constants: vec![],
-            globals: HashMap::new(),
-            builtins: HashMap::new(),
-            frames: vec![vm::StackEntry {
-                function: None,
-                pc: 0,
-                sp: 0,
-                result_register: 0,
-                caller_this: None,
-                current_this: None,
-                file: Some(get_absolute_path()),
-            }],
-            activation_records: vec![],
-            catch_exceptions: vec![],
-        };
-        my_vm
-            .builtins
-            .insert("io".to_string(), value::Value::Native(native::IO::build().into()));
-        my_vm.builtins.insert(
-            "strings".to_string(),
-            value::Value::Native(native::Strings::build().into()),
-        );
-        my_vm.builtins.insert(
-            "type".to_string(),
-            value::Value::Native(native::Type::build().into()),
-        );
-        my_vm.builtins.insert(
-            "env".to_string(),
-            value::Value::Native(native::Env::build().into()),
-        );
-        my_vm.builtins.insert(
-            "import".to_string(),
-            value::Value::Native(native::Import::build().into()),
-        );
-        my_vm.builtins.insert(
-            "net".to_string(),
-            value::Value::Native(native::Net::build().into()),
-        );
-        my_vm.builtins.insert(
-            "re".to_string(),
-            value::Value::Native(native::Re::build().into()),
-        );
-        my_vm.builtins.insert(
-            "process".to_string(),
-            value::Value::Native(native::Process::build(embed::is_embedded()).into()),
-        );
-        my_vm.builtins.insert(
-            "lists".to_string(),
-            value::Value::Native(native::Lists::build().into()),
-        );
-        unsafe {
-            GLOBAL_INTERPRETER = Some(Interpreter {
-                vm: my_vm,
-                compiler: compiler,
-            })
+    /// Registers a module, its members are accessed as `name.member`.
+    pub fn register_module(&mut self, name: &str, members: HashMap<String, Value>) {
+        self.set_builtin(name, Value::Native(Rc::new(NativeValue::module(members))));
+    }
+
+    /// Looks up a global or builtin by name, dots access members of modules and objects, e.g. `routes.index`.
+    pub fn get_global(&self, path: &str) -> Option<Value> {
+        let mut names = path.split('.');
+        let name = names.next()?;
+        let mut value = self
+            .vm
+            .globals
+            .get(name)
+            .or_else(|| self.vm.builtins.get(name))?
+            .clone();
+        for name in names {
+            value = value.get(name.to_string()).ok()?;
+        }
+        Some(value)
+    }
+
+    /// Calls the function found at `path` (see `get_global`) with the given arguments.
+    pub fn call(&mut self, path: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let function = match self.get_global(path) {
+            Some(function) => function,
+            None => return Err(Error::Runtime(self.vm.exception(ERR_UNDEFINED_VAR, None))),
         };
+        self.vm.extern_call(function, args).map_err(Error::Runtime)
+    }
+
+    /// Compiles source code without running it. Imported modules are compiled
+    /// along with it, so that the bytecode doesn't depend on them being around.
+    pub fn compile(&mut self, source: String) -> Result<&compiler::Compiler, Error> {
+        let file_path = self.vm.compiler.file_path.clone();
+        self.vm.compiler.source = Some(Rc::from(source.as_str()));
+        let stmts = parse_source_code(&file_path, source.clone())?;
+        self.vm.compiler.compile(stmts).map_err(Error::Compile)?;
+        let root = bundle::root(&file_path);
+        bundle_modules(&mut self.vm, &root, file_path, &source)?;
+        Ok(&self.vm.compiler)
+    }
+
+    pub fn compile_to_bytecode(&mut self, source: String) -> Result<Vec<u8>, Error> {
+        self.compile_to_bytecode_with(source, &bytecode::Options::default())
+    }
+
+    /// Compiles to bytecode, optionally stripping source information and compressing it.
+    pub fn compile_to_bytecode_with(
+        &mut self,
+        source: String,
+        options: &bytecode::Options,
+    ) -> Result<Vec<u8>, Error> {
+        let compiler = self.compile(source)?;
+        Ok(bytecode::encode(compiler, options))
     }
+
+    /// Loads a compiled script without running it. Fails when it cannot be decoded, see
+    /// `bytecode::decode`, or when it refers to code or data that doesn't exist.
+    pub fn load_bytecode(&mut self, bytecode: &[u8]) -> Result<&compiler::Compiler, Error> {
+        let mut compiler = bytecode::decode(bytecode)?;
+        verifier::verify(&compiler)?;
+        compiler.file_path = self.vm.compiler.file_path.clone();
+        compiler.builtins = std::mem::take(&mut self.vm.compiler.builtins);
+        self.vm.compiler = compiler;
+        Ok(&self.vm.compiler)
+    }
+
+    /// Runs a compiled script, fails with `Error::InvalidBytecode` when the input is not bytecode.
+    pub fn run_interpreter_from_bytecode(&mut self, bytecode: &[u8]) -> Result<Value, Error> {
+        self.load_bytecode(bytecode)?;
+        self.run_main(0)
+    }
+
+    /// Compiles and runs source code. Globals defined by previous runs remain visible.
+    pub fn run_bytecode_interpreter(&mut self, source: String) -> Result<Value, Error> {
+        self.vm.compiler.source = Some(Rc::from(source.as_str()));
+        let stmts = parse_source_code(&self.vm.compiler.file_path, source)?;
+        self.run_stmts(stmts)
+    }
+
+    /// Like `run_bytecode_interpreter`, but when the last statement is an expression
+    /// its value is returned. Assignments evaluate to nil, as they do in the REPL.
+    pub fn evaluate(&mut self, source: String) -> Result<Value, Error> {
+        self.vm.compiler.source = Some(Rc::from(source.as_str()));
+        let mut stmts = parse_source_code(&self.vm.compiler.file_path, source)?;
+        if let Some(stmt::Stmt::Expr(stmt::ExprStmt {
+            expression,
+            last: Some(keyword),
+        })) = stmts.last()
+        {
+            if !matches!(expression, expr::Expr::Assign(_) | expr::Expr::Set(_)) {
+                let ret = stmt::Stmt::Return(stmt::ReturnStmt {
+                    keyword: keyword.clone(),
+                    value: Some(expression.clone()),
+                });
+                *stmts.last_mut().unwrap() = ret;
+            }
+        }
+        self.run_stmts(stmts)
+    }
+
+    fn run_stmts(&mut self, stmts: Vec<stmt::Stmt>) -> Result<Value, Error> {
+        let start_pc = self.vm.instructions.len();
+        let chunk_count = self.vm.compiler.contexts.last().map_or(0, |c| c.chunks.len());
+        if let Err(errors) = self.vm.compiler.compile(stmts) {
+            // Discard the partially compiled code, it should never run
+            self.vm.compiler.contexts.last_mut().unwrap().chunks.truncate(chunk_count);
+            return Err(Error::Compile(errors));
+        }
+        self.run_main(start_pc)
+    }
+
+    fn run_main(&mut self, start_pc: usize) -> Result<Value, Error> {
+        let main_context = self.vm.compiler.contexts.last().unwrap();
+        let register_count = main_context.register_count as usize;
+        let instructions: Vec<compiler::InstSrc> = main_context
+            .chunks
+            .iter()
+            .flat_map(|c| c.instructions.clone())
+            .collect();
+        self.vm.instructions = Rc::new(instructions.iter().map(|i| i.inst.clone()).collect());
+        self.vm.instructions_data = Rc::new(instructions.iter().map(|i| i.src.clone()).collect());
+        load_prototypes(&mut self.vm);
+
+        // Start over from the main frame, a previous run could have left an exception behind
+        self.vm.frames = vec![StackEntry {
+            function: None,
+            pc: start_pc,
+            sp: 0,
+            result_register: 0,
+            caller_this: None,
+            current_this: None,
+            file: Some(self.vm.compiler.file_path.clone()),
+            source: self.vm.compiler.source.clone(),
+            call_pc: None,
+            instructions_data: Some(self.vm.instructions_data.clone()),
+        }];
+        self.vm.catch_exceptions.clear();
+        self.vm.activation_records.truncate(register_count);
+        self.vm
+            .activation_records
+            .resize(register_count, Record::Val(Value::Nil));
+
+        self.vm.interpret().map_err(Error::Runtime)
+    }
+}
+
+fn load_prototypes(vm: &mut VM) {
+    vm.prototypes = Rc::new(
+        vm.compiler
+            .prototypes
+            .iter()
+            .map(|p| VMFnPrototype {
+                instructions: Rc::new(p.instructions.iter().cloned().collect()),
+                register_count: p.register_count,
+                upvalues: p.upvalues.clone(),
+                instruction_data: Rc::new(p.instruction_data.clone()),
+                param_count: p.param_count,
+                name: p.name.clone(),
+                file_path: p.file_path.clone(),
+                source: p.source.clone(),
+            })
+            .collect(),
+    );
+    vm.constants = vm.compiler.constants.iter().map(|c| c.into()).collect();
 }
 
-pub fn parse_source_code(source: String) -> Vec<stmt::Stmt> {
-    let state = &mut state::InterpreterState::new(source);
+pub fn parse_source_code(file_path: &str, source: String) -> Result<Vec<stmt::Stmt>, Error> {
+    let state = &mut state::InterpreterState::new(file_path.to_string(), source);
     let mut lex = lexer::Lexer::new(state);
     lex.scan();
     if !state.errors.is_empty() {
-        for err in &state.errors {
-            println!("Error on line {}\n\t{}", err.line, err.message);
-        }
-        std::process::exit(0);
+        return Err(Error::Syntax(state.errors.clone()));
     }
     let mut parser = parser::Parser::new(state);
-    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
-        parser.parse();
-    }));
-    if result.is_err() || !state.errors.is_empty() {
-        for err in &state.errors {
-            println!("Error on line {}\n\t{}", err.line, err.message);
-        }
-        std::process::exit(0);
+    parser.parse();
+    if !state.errors.is_empty() {
+        return Err(Error::Syntax(state.errors.clone()));
     }
-    return state.stmts.clone();
+    Ok(state.stmts.clone())
 }
 
-pub fn compile_to_bytecode(source: String) -> Vec<u8> {
-    let interpreter = get_global_interpreter();
-    let stmts = parse_source_code(source);
-    interpreter.compiler.compile(stmts);
-    bincode::serialize(&interpreter.compiler).unwrap()
+fn env_flag(name: &str) -> bool {
+    let value = env::var(name).unwrap_or("0".to_string());
+    value == "1" || value.eq_ignore_ascii_case("true")
 }
 
-pub fn run_interpreter_from_bytecode(bytecode: &[u8]) -> bool {
-    if let Ok(compiler) = bincode::deserialize::<compiler::Compiler>(bytecode) {
-        let interpreter = get_global_interpreter();
-        interpreter.compiler = compiler;
-        let instructions: Vec<compiler::InstSrc> = interpreter
-            .compiler
-            .contexts
-            .iter()
-            .map(|c| c.chunks.iter())
-            .flatten()
-            .map(|c| c.instructions.clone())
-            .flatten()
-            .collect();
-        interpreter.vm.instructions = Rc::new(instructions.iter().map(|i| i.inst.clone()).collect());
-        interpreter.vm.instructions_data = Rc::new(instructions.iter().map(|i| i.src.clone()).collect());
-        interpreter.vm.prototypes = Rc::new(interpreter.compiler.prototypes.iter().map(|p| VMFnPrototype{
-            instructions: Rc::new(p.instructions.clone()),
-            register_count: p.register_count,
-            upvalues: p.upvalues.clone(),
-            instruction_data: Rc::new(p.instruction_data.clone()),
-            param_count: p.param_count,
-            name: p.name.clone(),
-            file_path: p.file_path.clone(),
-        }).collect());
-        interpreter.vm.constants = interpreter
-            .compiler
-            .constants
-            .iter()
-            .map(|c| c.into())
-            .collect();
-        interpreter.vm.activation_records = Vec::with_capacity(20480);
-        interpreter.vm.activation_records.extend(
-            (0..interpreter.compiler.contexts.last().unwrap().register_count)
-                .map(|_| vm::Record::Val(value::Value::Nil))
-        );
-
-        interpreter.vm.interpret();
-        return true;
+/// Prints an error the way the command line reports it. Setting GROTSKY_PLAIN_ERRORS
+/// prints the short format instead of pointing at the source code.
+pub fn print_error(err: &Error) {
+    let plain = env_flag("GROTSKY_PLAIN_ERRORS");
+    let skip_backtrace = env_flag("GROTSKY_SKIP_BACKTRACE");
+    if let Error::Runtime(exc) = err {
+        if !plain || !skip_backtrace {
+            let mut causes = vec![];
+            let mut cause = &exc.err.cause;
+            while let Some(err) = cause {
+                causes.push(err);
+                cause = &err.cause;
+            }
+            for err in causes.iter().rev() {
+                if !skip_backtrace && !err.stack.is_empty() {
+                    print!("{}", err.traceback());
+                }
+                if plain {
+                    println!("{}: {}", err.kind.name(), err.msg);
+                } else {
+                    print!("{}", err.render());
+                }
+                println!("\nThe above error caused the following error:\n");
+            }
+        }
+        if !skip_backtrace {
+            print!("{}", exc.err.traceback());
+        }
+    }
+    if plain {
+        print!("{}", err);
+    } else {
+        print!("{}", err.render());
     }
-    return false;
 }
 
-pub fn run_bytecode_interpreter(source: String) {
-    let interpreter = get_global_interpreter();
-    let stmts = parse_source_code(source);
-    interpreter.compiler.compile(stmts);
-    let instructions: Vec<compiler::InstSrc> = interpreter
-        .compiler
-        .contexts
-        .iter()
-        .map(|c| c.chunks.iter())
-        .flatten()
-        .map(|c| c.instructions.clone())
-        .flatten()
-        .collect();
-    interpreter.vm.instructions = Rc::new(instructions.iter().map(|i| i.inst.clone()).collect());
-    interpreter.vm.instructions_data = Rc::new(instructions.iter().map(|i| i.src.clone()).collect());
-    interpreter.vm.prototypes = Rc::new(interpreter.compiler.prototypes.iter().map(|p| VMFnPrototype{
-        instructions: Rc::new(p.instructions.clone()),
-        register_count: p.register_count,
-        upvalues: p.upvalues.clone(),
-        instruction_data: Rc::new(p.instruction_data.clone()),
-        param_count: p.param_count,
-        name: p.name.clone(),
-        file_path: p.file_path.clone(),
-    }).collect());
-    interpreter.vm.constants = interpreter
-        .compiler
-        .constants
-        .iter()
-        .map(|c| c.into())
-        .collect();
-    interpreter.vm.activation_records = Vec::with_capacity(20480);
-    interpreter.vm.activation_records.extend(
-        (0..interpreter.compiler.contexts.last().unwrap().register_count)
-            .map(|_| vm::Record::Val(value::Value::Nil))
-    );
-
-    interpreter.vm.interpret();
+pub fn import_module(vm: &mut VM, source: String) -> Result<HashMap<String, Value>, Error> {
+    isolate_module(vm, |vm| {
+        let context = compile_module(vm, source)?;
+        run_module(vm, context)
+    })
 }
 
-pub fn import_module(source: String) -> HashMap<String, value::Value> {
-    let interpreter = get_global_interpreter();
+/// Runs a module that was compiled ahead of time into the same bytecode.
+pub fn import_bundled_module(
+    vm: &mut VM,
+    context: compiler::FnContext,
+) -> Result<HashMap<String, Value>, Error> {
+    isolate_module(vm, |vm| run_module(vm, context))
+}
 
+// Modules are compiled and run from a clean state, the importing script
+// continues as if nothing happened
+fn isolate_module<T>(
+    vm: &mut VM,
+    f: impl FnOnce(&mut VM) -> Result<T, Error>,
+) -> Result<T, Error> {
     // Store current interpreter state
-    let saved_fn_contexts = interpreter.compiler.contexts.clone();
-    let saved_compiler_globals = interpreter.compiler.globals.clone();
-    let saved_instructions = interpreter.vm.instructions.clone();
-    let saved_instructions_data = interpreter.vm.instructions_data.clone();
-    let saved_activation_records = interpreter.vm.activation_records.clone();
-    let saved_globals = interpreter.vm.globals.clone();
-    let saved_frames = interpreter.vm.frames.clone();
-
-    let stmts = parse_source_code(source);
-    interpreter.compiler.contexts = vec![];
-    interpreter.compiler.globals = HashSet::new();
-    interpreter.compiler.enter_function("".to_string());
-    interpreter.compiler.enter_function("module".to_string());
-    interpreter.compiler.compile(stmts);
-    let module_global_context = interpreter.compiler.contexts[1].clone();
-    interpreter.compiler.leave_function(0);
-    interpreter.compiler.leave_function(0);
+    let saved_fn_contexts = vm.compiler.contexts.clone();
+    let saved_compiler_globals = vm.compiler.globals.clone();
+    let saved_instructions = vm.instructions.clone();
+    let saved_instructions_data = vm.instructions_data.clone();
+    let saved_activation_records = vm.activation_records.clone();
+    let saved_globals = vm.globals.clone();
+    let saved_frames = vm.frames.clone();
+    // Handlers of the importing script must not catch errors raised by the module
+    let saved_catch_exceptions = std::mem::take(&mut vm.catch_exceptions);
+
+    let result = f(vm);
+
+    // Restore saved state
+    vm.compiler.contexts = saved_fn_contexts;
+    vm.compiler.globals = saved_compiler_globals;
+    vm.instructions = saved_instructions;
+    vm.instructions_data = saved_instructions_data;
+    vm.activation_records = saved_activation_records;
+    vm.globals = saved_globals;
+    vm.frames = saved_frames;
+    vm.catch_exceptions = saved_catch_exceptions;
 
+    result
+}
+
+// Compiles the module found at vm.compiler.file_path, its functions and constants
+// are added to the compiler, the top level code is returned
+fn compile_module(vm: &mut VM, source: String) -> Result<compiler::FnContext, Error> {
+    vm.compiler.source = Some(Rc::from(source.as_str()));
+    let stmts = parse_source_code(&vm.compiler.file_path, source)?;
+    vm.compiler.contexts = vec![];
+    vm.compiler.globals = HashSet::new();
+    vm.compiler.enter_function("".to_string());
+    vm.compiler.enter_function("module".to_string());
+    vm.compiler.compile(stmts).map_err(Error::Compile)?;
+    let module_global_context = vm.compiler.contexts[1].clone();
+    vm.compiler.leave_function(0);
+    vm.compiler.leave_function(0);
+    Ok(module_global_context)
+}
+
+fn run_module(
+    vm: &mut VM,
+    module_global_context: compiler::FnContext,
+) -> Result<HashMap<String, Value>, Error> {
     let instructions: Vec<compiler::InstSrc> = module_global_context
         .chunks
         .iter()
         .map(|c| c.instructions.clone())
         .flatten()
         .collect();
-    interpreter.vm.instructions = Rc::new(instructions.iter().map(|i| i.inst.clone()).collect());
-    interpreter.vm.instructions_data = Rc::new(instructions.iter().map(|i| i.src.clone()).collect());
-    interpreter.vm.prototypes = Rc::new(interpreter.compiler.prototypes.iter().map(|p| VMFnPrototype{
-        instructions: Rc::new(p.instructions.clone()),
-        register_count: p.register_count,
-        upvalues: p.upvalues.clone(),
-        instruction_data: Rc::new(p.instruction_data.clone()),
-        param_count: p.param_count,
-        name: p.name.clone(),
-        file_path: p.file_path.clone(),
-    }).collect());
-    interpreter.vm.constants = interpreter
-        .compiler
-        .constants
-        .iter()
-        .map(|c| c.into())
-        .collect();
-    interpreter.vm.activation_records = Vec::with_capacity(20480);
-    interpreter.vm.activation_records.extend(
-        (0..module_global_context.register_count)
-            .map(|_| vm::Record::Val(value::Value::Nil))
+    vm.instructions = Rc::new(instructions.iter().map(|i| i.inst.clone()).collect());
+    vm.instructions_data = Rc::new(instructions.iter().map(|i| i.src.clone()).collect());
+    load_prototypes(vm);
+    vm.activation_records = Vec::with_capacity(20480);
+    vm.activation_records.extend(
+        (0..module_global_context.register_count).map(|_| Record::Val(Value::Nil)),
     );
-    interpreter.vm.globals = HashMap::new();
-    interpreter.vm.frames.push(StackEntry {
+    vm.globals = HashMap::new();
+    vm.frames.push(StackEntry {
         function: None,
         pc: 0,
         sp: 0,
         result_register: 0,
         caller_this: None,
         current_this: None,
-        file: Some(get_absolute_path()),
+        file: Some(vm.compiler.file_path.clone()),
+        source: vm.compiler.source.clone(),
+        call_pc: vm.native_call_pc,
+        instructions_data: Some(vm.instructions_data.clone()),
     });
-    interpreter.vm.interpret();
+    vm.interpret().map_err(Error::Runtime)?;
 
     let module_exports = module_global_context.blocks[0]
         .locals
@@ -282,19 +397,241 @@ pub fn import_module(source: String) -> HashMap<String, value::Value> {
         .map(|l| {
             (
                 l.var_name.clone(),
-                interpreter.vm.activation_records[l.reg as usize].as_val(),
+                vm.activation_records[l.reg as usize].as_val(),
             )
         })
         .collect();
+    Ok(module_exports)
+}
 
-    // Restore saved state
-    interpreter.compiler.contexts = saved_fn_contexts;
-    interpreter.compiler.globals = saved_compiler_globals;
-    interpreter.vm.instructions = saved_instructions;
-    interpreter.vm.instructions_data = saved_instructions_data;
-    interpreter.vm.activation_records = saved_activation_records;
-    interpreter.vm.globals = saved_globals;
-    interpreter.vm.frames = saved_frames;
-
-    return module_exports;
+/// Compiles the modules imported by the script, and the ones they import, into
+/// the same bytecode. Only imports of string literals are followed.
+fn bundle_modules(vm: &mut VM, root: &Path, file_path: String, source: &str) -> Result<(), Error> {
+    for module_path in bundle::find_imports(source) {
+        let path = bundle::resolve(&file_path, &module_path);
+        let key = match bundle::module_key(root, &path) {
+            Some(key) => key,
+            None => continue,
+        };
+        if vm.compiler.modules.contains_key(&key) {
+            continue;
+        }
+        // Missing files are left for the import to report at runtime
+        let module_source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(_) => continue,
+        };
+        let module_file = path.to_string_lossy().to_string();
+        let saved_file_path = std::mem::replace(&mut vm.compiler.file_path, module_file.clone());
+        let saved_source = vm.compiler.source.clone();
+        let result = isolate_module(vm, |vm| compile_module(vm, module_source.clone()));
+        vm.compiler.file_path = saved_file_path;
+        vm.compiler.source = saved_source;
+        vm.compiler.modules.insert(key, result?);
+        bundle_modules(vm, root, module_file, &module_source)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_number(result: Result<Value, Error>) -> f64 {
+        match result {
+            Ok(Value::Number(n)) => n.n,
+            other => panic!("Expected a number, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_independent_interpreters() {
+        let mut first = Runtime::new("first.gr".to_string());
+        let mut second = Runtime::new("second.gr".to_string());
+        assert_eq!(as_number(first.run_bytecode_interpreter("let a = 1\nreturn a".to_string())), 1.0);
+        assert_eq!(as_number(second.run_bytecode_interpreter("let a = 2\nreturn a".to_string())), 2.0);
+        assert_eq!(as_number(first.run_bytecode_interpreter("return a + 10".to_string())), 11.0);
+    }
+
+    #[test]
+    fn test_register_natives() {
+        let mut runtime = Runtime::new("test.gr".to_string());
+        let calls = Rc::new(std::cell::RefCell::new(vec![]));
+        let recorded = calls.clone();
+        runtime.register_function("record", move |_vm, values| {
+            recorded.borrow_mut().extend(values);
+            Ok(Value::Nil)
+        });
+        let mut members = HashMap::new();
+        members.insert(
+            "answer".to_string(),
+            Value::Native(Rc::new(NativeValue::function(|_vm, _values| {
+                Ok(Value::Number(crate::value::NumberValue { n: 42.0 }))
+            }))),
+        );
+        runtime.register_module("host", members);
+        let result = runtime.run_bytecode_interpreter("record(1, 2)\nreturn host.answer()".to_string());
+        assert_eq!(as_number(result), 42.0);
+        assert_eq!(calls.borrow().len(), 2);
+
+        assert!(runtime.remove_builtin("io").is_some());
+        assert!(matches!(
+            runtime.run_bytecode_interpreter("io.println(1)".to_string()),
+            Err(Error::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn test_call_from_rust() {
+        let mut runtime = Runtime::new("test.gr".to_string());
+        let source = r#"
+            let offset = 10
+            fn add(a, b) a + b + offset
+            class Calc {
+                init(n) {
+                    this.n = n
+                }
+                double() {
+                    return this.n * 2
+                }
+            }
+            let seven = Calc(7)
+            fn fails() 1 + "a"
+        "#;
+        assert!(runtime.run_bytecode_interpreter(source.to_string()).is_ok());
+        let args = vec![
+            Value::Number(crate::value::NumberValue { n: 1.0 }),
+            Value::Number(crate::value::NumberValue { n: 2.0 }),
+        ];
+        assert_eq!(as_number(runtime.call("add", args.clone())), 13.0);
+        assert_eq!(as_number(runtime.call("seven.double", vec![])), 14.0);
+        assert!(matches!(runtime.call("fails", vec![]), Err(Error::Runtime(_))));
+        assert!(matches!(runtime.call("add", vec![]), Err(Error::Runtime(_))));
+        assert!(matches!(runtime.call("missing", args), Err(Error::Runtime(_))));
+        // The runtime is still usable after a failed call
+        assert_eq!(as_number(runtime.run_bytecode_interpreter("return add(0, 0)".to_string())), 10.0);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut runtime = Runtime::new("<repl>".to_string());
+        assert!(matches!(runtime.evaluate("let a = 2".to_string()), Ok(Value::Nil)));
+        assert_eq!(as_number(runtime.evaluate("a * 3".to_string())), 6.0);
+        assert!(matches!(runtime.evaluate("a = 5".to_string()), Ok(Value::Nil)));
+        assert_eq!(as_number(runtime.evaluate("fn f() {\n return a\n}\nf()".to_string())), 5.0);
+    }
+
+    #[test]
+    fn test_errors_are_returned() {
+        let mut interpreter = Runtime::new("test.gr".to_string());
+        match interpreter.run_bytecode_interpreter("let a = 1\nio.println(a ! 1)".to_string()) {
+            Err(Error::Syntax(errors)) => {
+                assert_eq!(errors[0].file, "test.gr");
+                assert_eq!((errors[0].line, errors[0].column), (2, 14));
+            }
+            _ => panic!("expected a syntax error"),
+        }
+        assert!(matches!(
+            interpreter.run_bytecode_interpreter("let x = y".to_string()),
+            Err(Error::Compile(_))
+        ));
+        assert!(matches!(
+            interpreter.run_bytecode_interpreter("let z = 1 + \"a\"".to_string()),
+            Err(Error::Runtime(_))
+        ));
+        assert!(matches!(
+            interpreter.run_interpreter_from_bytecode(b"not bytecode"),
+            Err(Error::InvalidBytecode)
+        ));
+    }
+
+    #[test]
+    fn test_errors_show_compiled_source() {
+        let source = "let a = 1\nlet b = a + nil\nlet c = d\n";
+        let mut runtime = Runtime::new("not_on_disk.gr".to_string());
+        let err = runtime.run_bytecode_interpreter(source.to_string()).unwrap_err();
+        assert!(err.render().contains("3 | let c = d"));
+        let source = "let a = 1\nlet b = a + nil\n";
+        let err = runtime.run_bytecode_interpreter(source.to_string()).unwrap_err();
+        assert!(err.render().contains("2 | let b = a + nil"));
+
+        // Bytecode doesn't carry the source, only the location is shown
+        let bytecode = Runtime::new("not_on_disk.gr".to_string())
+            .compile_to_bytecode(source.to_string())
+            .unwrap();
+        let err = Runtime::new("not_on_disk.gr".to_string())
+            .run_interpreter_from_bytecode(&bytecode)
+            .unwrap_err();
+        assert_eq!(
+            err.render(),
+            "error[TypeError]: A number was expected at this position\n --> not_on_disk.gr:2:11\n"
+        );
+    }
+
+    #[test]
+    fn test_all_syntax_errors_are_reported() {
+        let mut interpreter = Runtime::new("test.gr".to_string());
+        let source = r#"let a = )
+fn f(a b) {
+    return a
+}
+if true {
+    let = 1
+    io.println(a)
+}
+let d = {"a": )}
+io.println(d"#;
+        match interpreter.run_bytecode_interpreter(source.to_string()) {
+            Err(Error::Syntax(errors)) => {
+                let lines: Vec<i32> = errors.iter().map(|err| err.line).collect();
+                assert_eq!(lines, vec![1, 2, 6, 9, 10]);
+            }
+            _ => panic!("expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn test_syntax_errors_between_blank_lines() {
+        let mut interpreter = Runtime::new("test.gr".to_string());
+        let source = "let a = 1\n\n\nlet b = )\n\nfor 1; i < 2; i = i + 1 {}\n\n";
+        match interpreter.run_bytecode_interpreter(source.to_string()) {
+            Err(Error::Syntax(errors)) => {
+                let lines: Vec<i32> = errors.iter().map(|err| err.line).collect();
+                assert_eq!(lines, vec![4, 6, 6]);
+            }
+            _ => panic!("expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn test_caught_error_fields() {
+        let mut runtime = Runtime::new("test.gr".to_string());
+        let source = r#"
+fn fail() {
+    return 1 + "a"
+}
+let caught = nil
+try {
+    fail()
+} catch err {
+    caught = err
+}
+let text = "Error: " + caught.message
+let same = caught.message == "A number was expected at this position"
+"#;
+        runtime.run_bytecode_interpreter(source.to_string()).unwrap();
+        assert_eq!(
+            runtime.get_global("text").unwrap().string(),
+            "Error: A number was expected at this position"
+        );
+        assert_eq!(runtime.get_global("same").unwrap().string(), "true");
+        let mut err = runtime.get_global("caught").unwrap();
+        assert_eq!(err.get("kind".to_string()).unwrap().string(), "TypeError");
+        assert_eq!(err.get("message".to_string()).unwrap().string(), "A number was expected at this position");
+        assert_eq!(as_number(Ok(err.get("line".to_string()).unwrap())), 3.0);
+        assert_eq!(
+            err.get("stack".to_string()).unwrap().string(),
+            "[\"File \\\"test.gr\\\", line 7, in <main>\", \"File \\\"test.gr\\\", line 3, in fail\"]"
+        );
+    }
 }
@@ -8,6 +8,9 @@ pub struct Lexer<'a> {
     start: usize,
     current: usize,
     line: i32,
+    // Columns of start and current, counting characters from 1
+    start_column: i32,
+    column: i32,
 
     state: &'a mut InterpreterState,
 }
@@ -36,6 +39,8 @@ fn keywords() -> HashMap<&'static str, Token> {
         ("in", Token::In),
         ("try", Token::Try),
         ("catch", Token::Catch),
+        ("finally", Token::Finally),
+        ("throw", Token::Throw),
     ])
 }
 
@@ -45,6 +50,8 @@ impl Lexer<'_> {
             start: 0,
             current: 0,
             line: 1,
+            start_column: 1,
+            column: 1,
             state: state,
         }
     }
@@ -60,9 +67,20 @@ impl Lexer<'_> {
     fn advance(&mut self) -> u8 {
         let c = self.next();
         self.current += 1;
+        if c == b'\n' {
+            self.column = 1;
+        } else if c & 0xc0 != 0x80 {
+            // Continuation bytes belong to the previous character
+            self.column += 1;
+        }
         return c;
     }
 
+    fn start_token(&mut self) {
+        self.start = self.current;
+        self.start_column = self.column;
+    }
+
     fn matches(&self, c: char) -> bool {
         if self.is_at_end() {
             return false;
@@ -70,6 +88,10 @@ impl Lexer<'_> {
         return char::from(self.next()) == c;
     }
 
+    fn matches_str(&self, s: &str) -> bool {
+        self.state.source.as_bytes()[self.current..].starts_with(s.as_bytes())
+    }
+
     fn is_digit(&self, c: u8) -> bool {
         let zero: u8 = 48;
         let nine: u8 = 57;
@@ -94,12 +116,23 @@ impl Lexer<'_> {
             lexeme: lexeme,
             literal: l,
             line: self.line,
+            column: self.start_column,
         })
     }
 
+    // Arithmetic operators followed by '=' are compound assignments
+    fn operator(&mut self, token: Token, assign_token: Token) {
+        if self.matches('=') {
+            self.advance();
+            self.emit(assign_token, None);
+        } else {
+            self.emit(token, None);
+        }
+    }
+
     pub fn scan(&mut self) {
         while !self.is_at_end() {
-            self.start = self.current;
+            self.start_token();
             self.scan_token();
         }
         let token_count = self.state.tokens.len();
@@ -111,6 +144,7 @@ impl Lexer<'_> {
                 lexeme: "".to_string(),
                 literal: None,
                 line: self.line,
+                column: self.column,
             })
         }
         self.state.tokens.push(TokenData {
@@ -118,6 +152,7 @@ impl Lexer<'_> {
             lexeme: "".to_string(),
             literal: None,
             line: self.line,
+            column: self.column,
         })
     }
 
@@ -132,14 +167,15 @@ impl Lexer<'_> {
             ')' => self.emit(Token::RightParen, None),
             ',' => self.emit(Token::Comma, None),
             '.' => self.emit(Token::Dot, None),
-            '-' => self.emit(Token::Minus, None),
-            '+' => self.emit(Token::Plus, None),
-            '/' => self.emit(Token::Slash, None),
-            '%' => self.emit(Token::Mod, None),
-            '*' => self.emit(Token::Star, None),
-            '^' => self.emit(Token::Power, None),
+            '-' => self.operator(Token::Minus, Token::MinusEqual),
+            '+' => self.operator(Token::Plus, Token::PlusEqual),
+            '/' => self.operator(Token::Slash, Token::SlashEqual),
+            '%' => self.operator(Token::Mod, Token::ModEqual),
+            '*' => self.operator(Token::Star, Token::StarEqual),
+            '^' => self.operator(Token::Power, Token::PowerEqual),
             ':' => self.emit(Token::Colon, None),
             ';' => self.emit(Token::Semicolon, None),
+            '?' => self.emit(Token::Question, None),
             '#' => {
                 while !self.matches('\n') && !self.is_at_end() {
                     self.advance();
@@ -150,11 +186,11 @@ impl Lexer<'_> {
                     self.advance();
                     self.emit(Token::BangEqual, None);
                 } else {
-                    self.state.set_error(InterpreterError {
-                        message: "'!' cannot be used here".to_string(),
-                        line: self.line,
-                        pos: self.start,
-                    });
+                    self.state.set_error(InterpreterError::new(
+                        "'!' cannot be used here".to_string(),
+                        self.line,
+                        self.start_column,
+                    ));
                 }
             }
             '=' => {
@@ -192,7 +228,29 @@ impl Lexer<'_> {
                 self.emit(Token::Newline, None);
             }
 
-            '"' => self.string(),
+            '"' => {
+                if self.matches_str("\"\"") {
+                    self.advance();
+                    self.advance();
+                    self.multiline_string(false);
+                } else {
+                    self.string(false);
+                }
+            }
+            'r' if self.matches('"') => {
+                self.advance();
+                if self.matches_str("\"\"") {
+                    self.advance();
+                    self.advance();
+                    self.multiline_string(true);
+                } else {
+                    self.string(true);
+                }
+            }
+            'f' if self.matches('"') => {
+                self.advance();
+                self.fstring();
+            }
 
             _ => {
                 if self.is_digit(c) {
@@ -200,53 +258,263 @@ impl Lexer<'_> {
                 } else if self.is_alpha(c) {
                     self.identifier();
                 } else {
-                    self.state.set_error(InterpreterError {
-                        message: "Illegal character".to_string(),
-                        line: self.line,
-                        pos: self.start,
-                    })
+                    self.state.set_error(InterpreterError::new(
+                        "Illegal character".to_string(),
+                        self.line,
+                        self.start_column,
+                    ))
                 }
             }
         }
     }
 
-    fn string(&mut self) {
-        let mut lit = "".to_string();
+    // Raw strings keep backslashes as they are
+    fn string(&mut self, raw: bool) {
+        let mut lit: Vec<u8> = vec![];
         while !self.is_at_end() && !self.matches('"') {
             if self.matches('\n') {
                 self.line += 1;
             }
-            if self.matches('\\') {
+            if !raw && self.matches('\\') {
+                let escape_column = self.column;
                 self.advance();
                 let unescaped = self.unescape_sequence();
-                if unescaped == "".to_string() {
-                    self.state.set_error(InterpreterError {
-                        message: "Closing \" was expected".to_string(),
-                        line: self.line,
-                        pos: self.start,
-                    });
+                if unescaped.is_empty() {
+                    self.invalid_escape(escape_column);
+                    self.skip_string("\"");
                     return;
                 }
-                lit.push_str(&unescaped);
+                lit.extend_from_slice(unescaped.as_bytes());
                 continue;
             }
-            lit.push(self.state.source.as_bytes()[self.current].into());
-            self.advance();
+            lit.push(self.advance());
         }
 
         if self.is_at_end() {
-            self.state.set_error(InterpreterError {
-                message: "Closing \" was expected".to_string(),
-                line: self.line,
-                pos: self.start,
-            });
+            self.state.set_error(InterpreterError::new(
+                "Closing \" was expected".to_string(),
+                self.line,
+                self.start_column,
+            ));
             return;
         }
 
         // Consume ending "
         self.advance();
 
-        self.emit(Token::String, Some(Literal::String(lit)));
+        let text = String::from_utf8_lossy(&lit).to_string();
+        self.emit(Token::String, Some(Literal::String(text)));
+    }
+
+    // Triple quoted strings can span many lines and contain quotes. A line break
+    // right after the opening quotes is dropped, and so is the indentation shared
+    // by all the lines that are not blank
+    fn multiline_string(&mut self, raw: bool) {
+        let line = self.line;
+        if self.matches_str("\r\n") {
+            self.advance();
+        }
+        if self.matches('\n') {
+            self.advance();
+            self.line += 1;
+        }
+
+        let end = match self.find_closing_quotes(raw) {
+            Some(end) => end,
+            None => {
+                self.state.set_error(InterpreterError::new(
+                    "Closing \"\"\" was expected".to_string(),
+                    line,
+                    self.start_column,
+                ));
+                while !self.is_at_end() {
+                    self.advance();
+                }
+                return;
+            }
+        };
+        let indent = common_indent(&self.state.source.as_bytes()[self.current..end]);
+
+        let mut lit: Vec<u8> = vec![];
+        let mut line_start = true;
+        while self.current < end {
+            if line_start {
+                line_start = false;
+                let rest = &self.state.source.as_bytes()[self.current..end];
+                let line_len = rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len());
+                let skip = if is_blank(&rest[..line_len]) { line_len } else { indent };
+                for _ in 0..skip {
+                    self.advance();
+                }
+                continue;
+            }
+            if self.matches('\n') {
+                self.line += 1;
+                line_start = true;
+            }
+            if !raw && self.matches('\\') {
+                let escape_column = self.column;
+                self.advance();
+                let unescaped = self.unescape_sequence();
+                if unescaped.is_empty() {
+                    self.invalid_escape(escape_column);
+                    self.skip_string("\"\"\"");
+                    return;
+                }
+                lit.extend_from_slice(unescaped.as_bytes());
+                continue;
+            }
+            lit.push(self.advance());
+        }
+
+        // Consume ending """
+        for _ in 0..3 {
+            self.advance();
+        }
+
+        let text = String::from_utf8_lossy(&lit).to_string();
+        self.emit(Token::String, Some(Literal::String(text)));
+    }
+
+    fn invalid_escape(&mut self, column: i32) {
+        self.state.set_error(InterpreterError::new(
+            "Invalid escape sequence".to_string(),
+            self.line,
+            column,
+        ));
+    }
+
+    // Moves past the quotes that close the string being scanned, so the rest of
+    // it isn't read as code
+    fn skip_string(&mut self, quotes: &str) {
+        while !self.is_at_end() && !self.matches_str(quotes) {
+            if self.matches('\\') {
+                self.advance();
+                if self.is_at_end() {
+                    break;
+                }
+            }
+            if self.advance() == b'\n' {
+                self.line += 1;
+            }
+        }
+        for _ in 0..quotes.len() {
+            if !self.is_at_end() {
+                self.advance();
+            }
+        }
+    }
+
+    // Position of the """ that closes the string being scanned
+    fn find_closing_quotes(&self, raw: bool) -> Option<usize> {
+        let source = self.state.source.as_bytes();
+        let mut pos = self.current;
+        while pos < source.len() {
+            if source[pos..].starts_with(b"\"\"\"") {
+                return Some(pos);
+            }
+            // Escaped quotes don't close the string
+            if !raw && source[pos] == b'\\' {
+                pos += 1;
+            }
+            pos += 1;
+        }
+        None
+    }
+
+    // Emits the text before every {expression} as FStringStart or FStringMiddle,
+    // followed by the tokens of the expression, and FStringEnd after the closing "
+    fn fstring(&mut self) {
+        let start_column = self.start_column;
+        let mut token = Token::FStringStart;
+        loop {
+            let mut lit: Vec<u8> = vec![];
+            let mut expression = false;
+            while !self.is_at_end() && !self.matches('"') {
+                if self.matches('{') || self.matches('}') {
+                    let brace_column = self.column;
+                    let brace = self.advance();
+                    // Doubled braces stand for themselves
+                    if self.matches(char::from(brace)) {
+                        self.advance();
+                        lit.push(brace);
+                        continue;
+                    }
+                    if brace == b'}' {
+                        self.state.set_error(InterpreterError::new(
+                            "Single '}' is not allowed in f-string, use '}}' instead".to_string(),
+                            self.line,
+                            brace_column,
+                        ));
+                        self.skip_string("\"");
+                        return;
+                    }
+                    expression = true;
+                    break;
+                }
+                if self.matches('\n') {
+                    self.line += 1;
+                }
+                if self.matches('\\') {
+                    let escape_column = self.column;
+                    self.advance();
+                    let unescaped = self.unescape_sequence();
+                    if unescaped.is_empty() {
+                        self.invalid_escape(escape_column);
+                        self.skip_string("\"");
+                        return;
+                    }
+                    lit.extend_from_slice(unescaped.as_bytes());
+                    continue;
+                }
+                lit.push(self.advance());
+            }
+
+            if self.is_at_end() {
+                self.state.set_error(InterpreterError::new(
+                    "Closing \" was expected".to_string(),
+                    self.line,
+                    start_column,
+                ));
+                return;
+            }
+
+            let text = String::from_utf8_lossy(&lit).to_string();
+            self.emit(token, Some(Literal::String(text)));
+            if !expression {
+                // Consume ending "
+                self.start_token();
+                self.advance();
+                self.emit(Token::FStringEnd, None);
+                return;
+            }
+
+            // Tokens of the expression, up to the '}' that closes it
+            let mut depth = 0;
+            loop {
+                if self.is_at_end() {
+                    self.state.set_error(InterpreterError::new(
+                        "Closing } was expected in f-string".to_string(),
+                        self.line,
+                        start_column,
+                    ));
+                    return;
+                }
+                if self.matches('}') {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                } else if self.matches('{') {
+                    depth += 1;
+                }
+                self.start_token();
+                self.scan_token();
+            }
+            self.start_token();
+            self.advance();
+            token = Token::FStringMiddle;
+        }
     }
 
     fn unescape_sequence(&mut self) -> String {
@@ -305,3 +573,143 @@ impl Lexer<'_> {
         self.emit(token.clone(), None);
     }
 }
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(|&c| c == b' ' || c == b'\t' || c == b'\r')
+}
+
+// Leading whitespace shared by the lines that are not blank, tabs and spaces
+// only match themselves
+fn common_indent(text: &[u8]) -> usize {
+    let mut lines = text.split(|&c| c == b'\n').filter(|line| !is_blank(line));
+    let first = match lines.next() {
+        Some(line) => line,
+        None => return 0,
+    };
+    let mut indent = first.iter().take_while(|&&c| c == b' ' || c == b'\t').count();
+    for line in lines {
+        indent = first[..indent].iter().zip(line.iter()).take_while(|(a, b)| a == b).count();
+    }
+    indent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(source: &str) -> InterpreterState {
+        let mut state = InterpreterState::new("test.gr".to_string(), source.to_string());
+        Lexer::new(&mut state).scan();
+        state
+    }
+
+    fn strings(state: &InterpreterState) -> Vec<String> {
+        state
+            .tokens
+            .iter()
+            .filter_map(|t| match &t.literal {
+                Some(Literal::String(s)) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_string_literals() {
+        let state = scan("\"\"\"\n        <p>\n          \"hi\"\\t\n\n        </p>\n        \"\"\"");
+        assert!(state.errors.is_empty());
+        assert_eq!(strings(&state), vec!["<p>\n  \"hi\"\t\n\n</p>\n"]);
+
+        let state = scan(r#"[r"\d+\n", r"""a\"b""", """x "y" z""", "é"]"#);
+        assert!(state.errors.is_empty());
+        assert_eq!(strings(&state), vec![r"\d+\n", r#"a\"b"#, r#"x "y" z"#, "é"]);
+    }
+
+    #[test]
+    fn test_multiline_string_indentation() {
+        // Tabs and spaces don't stand for each other
+        let state = scan("\"\"\"\n\t  a\n  \tb\n\"\"\"");
+        assert_eq!(strings(&state), vec!["\t  a\n  \tb\n"]);
+        let state = scan("\"\"\"\n\t\ta\n\t\t  b\n\n\"\"\"");
+        assert_eq!(strings(&state), vec!["a\n  b\n\n"]);
+    }
+
+    #[test]
+    fn test_invalid_escape() {
+        for source in ["let a = \"x\\qy\"\nlet b = 1", "let a = \"\"\"\nx\\qy\n\"\"\"\nlet b = 1"] {
+            let state = scan(source);
+            let errors: Vec<(&str, i32)> =
+                state.errors.iter().map(|e| (e.message.as_str(), e.column)).collect();
+            let column = if source.contains("\"\"\"") { 2 } else { 11 };
+            assert_eq!(errors, vec![("Invalid escape sequence", column)]);
+            // Scanning goes on after the string
+            let last = &state.tokens[state.tokens.len() - 3];
+            assert_eq!((last.token, last.line), (Token::Number, source.lines().count() as i32));
+        }
+        let state = scan("let s = \"\"\"\n  a\\q\n");
+        let messages: Vec<&str> = state.errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, vec!["Closing \"\"\" was expected"]);
+    }
+
+    #[test]
+    fn test_fstring() {
+        let state = scan("f\"a{x}b{{c}}{f\"{y}\"}\"");
+        assert!(state.errors.is_empty());
+        let tokens: Vec<Token> = state.tokens.iter().map(|t| t.token).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::FStringStart,
+                Token::Identifier,
+                Token::FStringMiddle,
+                Token::FStringStart,
+                Token::Identifier,
+                Token::FStringMiddle,
+                Token::FStringEnd,
+                Token::FStringMiddle,
+                Token::FStringEnd,
+                Token::Newline,
+                Token::EOF
+            ]
+        );
+        assert_eq!(strings(&state), vec!["a", "b{c}", "", "", ""]);
+
+        let errors = [
+            ("f\"é } b\"\nlet a = 1", "Single '}' is not allowed in f-string, use '}}' instead", 5),
+            ("f\"a {x} \\q\"\nlet a = 1", "Invalid escape sequence", 9),
+            ("f\"a {1 b", "Closing } was expected in f-string", 1),
+        ];
+        for (source, message, column) in errors {
+            let state = scan(source);
+            let errors: Vec<(&str, i32)> =
+                state.errors.iter().map(|e| (e.message.as_str(), e.column)).collect();
+            assert_eq!(errors, vec![(message, column)]);
+        }
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let state = scan("+= -= *= /= %= ^= + - * / % ^");
+        assert!(state.errors.is_empty());
+        let tokens: Vec<Token> = state.tokens.iter().map(|t| t.token).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::PlusEqual,
+                Token::MinusEqual,
+                Token::StarEqual,
+                Token::SlashEqual,
+                Token::ModEqual,
+                Token::PowerEqual,
+                Token::Plus,
+                Token::Minus,
+                Token::Star,
+                Token::Slash,
+                Token::Mod,
+                Token::Power,
+                Token::Newline,
+                Token::EOF
+            ]
+        );
+    }
+}
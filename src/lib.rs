@@ -0,0 +1,36 @@
+//! Grotsky programming language.
+//!
+//! Scripts are executed by a [`Runtime`], which owns all the state needed to
+//! compile and run them. Several runtimes can be used side by side.
+//!
+//! ```no_run
+//! let mut runtime = grotsky_rs::Runtime::new("script.gr".to_string());
+//! match runtime.run_bytecode_interpreter("io.println(1 + 1)".to_string()) {
+//!     Ok(value) => println!("{}", value.repr()),
+//!     Err(err) => print!("{}", err),
+//! }
+//! ```
+
+pub mod bundle;
+pub mod bytecode;
+pub mod compiler;
+pub mod disasm;
+pub mod embed;
+pub mod errors;
+pub mod expr;
+pub mod instruction;
+pub mod interpreter;
+pub mod lexer;
+pub mod native;
+pub mod parser;
+pub mod repl;
+pub mod state;
+pub mod stmt;
+pub mod token;
+pub mod value;
+pub mod verifier;
+pub mod vm;
+
+pub use errors::Error;
+pub use interpreter::Runtime;
+pub use value::Value;
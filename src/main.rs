@@ -1,37 +1,46 @@
-mod compiler;
-mod embed;
-mod errors;
-mod expr;
-mod instruction;
-mod interpreter;
-mod lexer;
-mod native;
-mod parser;
-mod state;
-mod stmt;
-mod token;
-mod value;
-mod vm;
+use grotsky_rs::errors::Error;
+use grotsky_rs::value::Value;
+use grotsky_rs::{bytecode, disasm, embed, interpreter, repl};
 
 #[cfg(feature = "profile")]
 use pprof::protos::Message;
 #[cfg(feature = "profile")]
 use std::io::Write;
 
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::{fs::{canonicalize, read, write}, process::exit};
 
 use std::{env, panic};
 
 const GENERAL_USAGE: &'static str = r##"Usage:
+    grotsky
     grotsky [script.gr | bytecode.grc]
-    grotsky compile script.gr
-    grotsky embed bytecode.grc
+    grotsky compile [--strip] [--compress] script.gr
+    grotsky disasm [script.gr | bytecode.grc]
+    grotsky embed [-o output] [--runtime grotsky] bytecode.grc [asset ...]
+    grotsky embed --info executable
+    grotsky embed --extract [-o output.grc] executable
 "##;
 
+fn exit_code(result: Result<Value, Error>) -> i32 {
+    match result {
+        Ok(_) => 0,
+        Err(err) => {
+            interpreter::print_error(&err);
+            err.exit_code()
+        }
+    }
+}
+
 fn main() {
     if embed::is_embedded() {
-        embed::execute_embedded();
-        return;
+        let result = embed::execute_embedded();
+        if let Err(Error::InvalidBytecode) = result {
+            println!("Could not read embedded script");
+            exit(1);
+        }
+        exit(exit_code(result));
     }
 
     #[cfg(feature = "profile")]
@@ -43,52 +52,141 @@ fn main() {
 
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        println!("{}", GENERAL_USAGE);
-        exit(1);
+        repl::start();
+        return;
     }
     let content: Vec<u8>;
-    let mut abs_path = if args[1] == "compile" || args[1] == "embed" {
-        if args.len() != 3 {
+    let is_subcommand = matches!(args[1].as_str(), "compile" | "embed" | "disasm");
+    let allowed_flags: &[&str] = match args[1].as_str() {
+        "compile" => &["--strip", "--compress"],
+        "embed" => &["--info", "--extract"],
+        _ => &[],
+    };
+    // Options followed by a value, like -o output
+    let allowed_options: &[&str] = match args[1].as_str() {
+        "embed" => &["-o", "--runtime"],
+        _ => &[],
+    };
+    let mut flags: Vec<&String> = vec![];
+    let mut options: HashMap<&str, &String> = HashMap::new();
+    let mut params: Vec<&String> = vec![];
+    let mut rest = args.iter().skip(2);
+    let mut valid_args = true;
+    while let Some(arg) = rest.next() {
+        if allowed_options.contains(&arg.as_str()) {
+            match rest.next() {
+                Some(value) => {
+                    options.insert(arg.as_str(), value);
+                }
+                None => valid_args = false,
+            }
+        } else if arg.starts_with('-') {
+            valid_args &= allowed_flags.contains(&arg.as_str());
+            flags.push(arg);
+        } else {
+            params.push(arg);
+        }
+    }
+    let has_flag = |flag: &str| flags.iter().any(|f| *f == flag);
+    // Only embed takes more than one file, the assets packed along with the script
+    let inspect_embedded = has_flag("--info") || has_flag("--extract");
+    let valid_params = match args[1].as_str() {
+        "embed" if !inspect_embedded => !params.is_empty(),
+        _ => params.len() == 1,
+    };
+    let mut abs_path = if is_subcommand {
+        if !valid_params || !valid_args {
             println!("{}", GENERAL_USAGE);
             exit(1);
         }
-        canonicalize(&args[2]).unwrap()
+        canonicalize(params[0]).unwrap()
     } else {
         canonicalize(&args[1]).unwrap()
     };
     let abs_path_str = abs_path.to_string_lossy();
     let abs_path_string = String::from(abs_path_str);
+    if args[1] == "embed" && inspect_embedded {
+        let result = if has_flag("--info") {
+            embed::info(&abs_path).map(|info| print!("{}", info))
+        } else {
+            let output = match options.get("-o") {
+                Some(output) => PathBuf::from(output),
+                None => abs_path.with_extension("grc"),
+            };
+            embed::extract(&abs_path, &output)
+        };
+        if let Err(err) = result {
+            exit(exit_code(Err(err)));
+        }
+        return;
+    }
     if args[1] == "embed" {
         if abs_path.extension().unwrap_or_default() != "grc" {
             println!("Can only embed a .grc file");
             exit(1);
         }
-        let mut new_abs_path = abs_path.clone();
-        new_abs_path.set_extension("exe");
-        embed::embed_file(abs_path_string, String::from(new_abs_path.to_string_lossy()));
+        let asset_paths: Vec<String> = params[1..].iter().map(|p| p.to_string()).collect();
+        let assets = match embed::collect_assets(&env::current_dir().unwrap(), &asset_paths) {
+            Ok(assets) => assets,
+            Err(err) => {
+                println!("Cannot read assets: {}", err);
+                exit(1);
+            }
+        };
+        let output = match options.get("-o") {
+            Some(output) => PathBuf::from(output),
+            None => abs_path.with_extension("exe"),
+        };
+        let runtime = match options.get("--runtime") {
+            Some(runtime) => PathBuf::from(runtime),
+            None => env::current_exe().unwrap(),
+        };
+        if let Err(err) = embed::embed_file(&abs_path, assets, &runtime, &output) {
+            println!("Cannot embed script: {}", err);
+            exit(1);
+        }
         return;
     }
 
-    interpreter::set_absolute_path(abs_path_string);
-    content = read(interpreter::get_absolute_path()).unwrap();
+    content = read(&abs_path).unwrap();
+    let mut runtime = interpreter::Runtime::new(abs_path_string);
     let grotsky_debug = env::var("GROTSKY_DEBUG").unwrap_or("0".to_string());
     if grotsky_debug != "1" && !grotsky_debug.eq_ignore_ascii_case("true") {
         // Disable rust backtrace
         panic::set_hook(Box::new(|_info| {}));
     }
-    if args[1] == "compile" {
+    // A .grc file that isn't valid bytecode is reported instead of being run as source
+    let is_bytecode =
+        bytecode::is_bytecode(&content) || abs_path.extension().unwrap_or_default() == "grc";
+    let mut code = 0;
+    if args[1] == "disasm" {
+        let compiler = if is_bytecode {
+            runtime.load_bytecode(content.as_slice())
+        } else {
+            runtime.compile(String::from_utf8(content).expect("Invalid input file"))
+        };
+        match compiler {
+            Ok(compiler) => print!("{}", disasm::disassemble(compiler)),
+            Err(err) => code = exit_code(Err(err)),
+        }
+    } else if args[1] == "compile" {
         abs_path.set_extension("grc");
-        write(
-            abs_path,
-            interpreter::compile_to_bytecode(
-                String::from_utf8(content).expect("Invalid input file"),
-            ),
-        )
-        .expect("Write bytecode file");
-    } else if !interpreter::run_interpreter_from_bytecode(content.as_slice()) {
-        interpreter::run_bytecode_interpreter(
-            String::from_utf8(content).expect("Invalid input file"),
-        );
+        let options = bytecode::Options {
+            strip: has_flag("--strip"),
+            compress: has_flag("--compress"),
+        };
+        let source = String::from_utf8(content).expect("Invalid input file");
+        match runtime.compile_to_bytecode_with(source, &options) {
+            Ok(bytecode) => write(abs_path, bytecode).expect("Write bytecode file"),
+            Err(err) => code = exit_code(Err(err)),
+        }
+    } else {
+        let result = if is_bytecode {
+            runtime.run_interpreter_from_bytecode(content.as_slice())
+        } else {
+            runtime.run_bytecode_interpreter(String::from_utf8(content).expect("Invalid input file"))
+        };
+        code = exit_code(result);
     }
 
     #[cfg(feature = "profile")]
@@ -119,4 +217,8 @@ fn main() {
             file.write_all(&content).unwrap();
         }
     }
+
+    if code != 0 {
+        exit(code);
+    }
 }
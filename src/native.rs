@@ -4,17 +4,19 @@ use std::io::{stdin, BufRead, Read, Write};
 use std::net::{Shutdown, ToSocketAddrs};
 use std::ops::DerefMut;
 use std::{
-    cell::RefCell, collections::HashMap, env, fs::canonicalize, ops::Deref, rc::Rc,
+    cell::RefCell, collections::{BTreeMap, HashMap}, env, ops::Deref, rc::Rc,
     time::SystemTime,
 };
 use regex::Regex;
 
-use crate::errors::{ERR_EXPECTED_LIST, ERR_EXPECTED_NUMBER, ERR_LIST_EMPTY};
+use crate::errors::{ErrorKind, ERR_EXPECTED_LIST, ERR_EXPECTED_NUMBER, ERR_LIST_EMPTY};
+use crate::compiler::FnContext;
+use crate::vm::VM;
 use crate::value::{BoolValue, BytesValue, DictValue};
 use crate::{
     errors::ERR_INVALID_NUMBER_ARGUMENTS,
     errors::{RuntimeErr, ERR_EXPECTED_OBJECT, ERR_EXPECTED_STRING},
-    interpreter,
+    bundle, interpreter,
     value::{ListValue, MutValue, NativeBaggage, NativeValue, NumberValue, StringValue, Value},
 };
 use std::fs;
@@ -23,7 +25,7 @@ use std::path::Path;
 pub struct IO {}
 
 impl IO {
-    fn println(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn println(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.is_empty() {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -36,7 +38,7 @@ impl IO {
         return Ok(Value::Nil);
     }
 
-    fn readln(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn readln(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if !values.is_empty() {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -48,16 +50,16 @@ impl IO {
             return Ok(Value::Nil);
         }
         let line_unwrapped = line.unwrap();
-        if line_unwrapped.is_err() {
-            return Err(RuntimeErr{
-                msg: "Could not read from stdin",
-                signal: None,
-            });
+        if let Err(e) = &line_unwrapped {
+            return Err(RuntimeErr::message(
+                ErrorKind::IO,
+                format!("Could not read from stdin: {}", e),
+            ));
         }
         return Ok(Value::String(MutValue::new(StringValue::new(line_unwrapped.unwrap()))));
     }
 
-    fn clock(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn clock(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if !values.is_empty() {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -69,7 +71,7 @@ impl IO {
         }));
     }
 
-    fn read_file(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn read_file(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -79,28 +81,26 @@ impl IO {
                 return Err(ERR_EXPECTED_STRING);
             }
         };
-        match fs::read_to_string(string_value.0.borrow().s.as_str()) {
+        let path = string_value.0.borrow().s.clone();
+        let read_error = |e: std::io::Error| {
+            RuntimeErr::message(ErrorKind::IO, format!("Cannot read file {}: {}", path, e))
+        };
+        match fs::read_to_string(&path) {
             Ok(content) => Ok(Value::String(MutValue::new(StringValue::new(content)))),
             Err(e) => {
                 if let std::io::ErrorKind::InvalidData = e.kind() {
-                    match fs::read(string_value.0.borrow().s.as_str()) {
+                    match fs::read(&path) {
                         Ok(c) => Ok(Value::Bytes(Rc::new(BytesValue { s: c }))),
-                        Err(_) => Err(RuntimeErr {
-                            msg: "Cannot read file",
-                            signal: None,
-                        }),
+                        Err(e) => Err(read_error(e)),
                     }
                 } else {
-                    Err(RuntimeErr {
-                        msg: "Cannot read file",
-                        signal: None,
-                    })
+                    Err(read_error(e))
                 }
             }
         }
     }
 
-    fn write_file(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn write_file(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -117,16 +117,17 @@ impl IO {
                 return Err(ERR_EXPECTED_STRING);
             }
         };
-        match fs::write(path.0.borrow().s.as_str(), &content) {
+        let path = path.0.borrow().s.clone();
+        match fs::write(&path, &content) {
             Ok(_) => Ok(Value::Nil),
-            Err(_) => Err(RuntimeErr {
-                msg: "Cannot write file",
-                signal: None,
-            }),
+            Err(e) => Err(RuntimeErr::message(
+                ErrorKind::IO,
+                format!("Cannot write file {}: {}", path, e),
+            )),
         }
     }
 
-    fn list_dir(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn list_dir(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -165,14 +166,14 @@ impl IO {
                 }
                 Ok(Value::List(MutValue::new(list)))
             }
-            Err(_) => Err(RuntimeErr {
-                msg: "Cannot read dir",
-                signal: None,
-            }),
+            Err(e) => Err(RuntimeErr::message(
+                ErrorKind::IO,
+                format!("Cannot read dir {}: {}", path.0.borrow().s, e),
+            )),
         }
     }
 
-    fn file_exists(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn file_exists(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -188,7 +189,7 @@ impl IO {
         }
     }
 
-    fn mkdir_all(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn mkdir_all(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -206,10 +207,10 @@ impl IO {
         };
         match fs::create_dir_all(&path.0.borrow().s) {
             Ok(_) => Ok(Value::Nil),
-            Err(_) => Err(RuntimeErr {
-                msg: "Cannot create file",
-                signal: None,
-            }),
+            Err(e) => Err(RuntimeErr::message(
+                ErrorKind::IO,
+                format!("Cannot create dir {}: {}", path.0.borrow().s, e),
+            )),
         }
     }
 
@@ -222,49 +223,49 @@ impl IO {
         };
         let println = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::println),
+            callable: Some(Rc::new(IO::println)),
             bind: false,
             baggage: None,
         });
         let readln = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::readln),
+            callable: Some(Rc::new(IO::readln)),
             bind: false,
             baggage: None,
         });
         let clock = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::clock),
+            callable: Some(Rc::new(IO::clock)),
             bind: false,
             baggage: None,
         });
         let read_file = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::read_file),
+            callable: Some(Rc::new(IO::read_file)),
             bind: false,
             baggage: None,
         });
         let write_file = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::write_file),
+            callable: Some(Rc::new(IO::write_file)),
             bind: false,
             baggage: None,
         });
         let list_dir = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::list_dir),
+            callable: Some(Rc::new(IO::list_dir)),
             bind: false,
             baggage: None,
         });
         let file_exists = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::file_exists),
+            callable: Some(Rc::new(IO::file_exists)),
             bind: false,
             baggage: None,
         });
         let mkdir_all = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&IO::mkdir_all),
+            callable: Some(Rc::new(IO::mkdir_all)),
             bind: false,
             baggage: None,
         });
@@ -290,7 +291,7 @@ impl IO {
 pub struct Strings {}
 
 impl Strings {
-    pub fn to_lower(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn to_lower(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -304,7 +305,7 @@ impl Strings {
         return Ok(Value::String(MutValue::new(StringValue::new(result))));
     }
 
-    pub fn to_upper(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn to_upper(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -318,7 +319,7 @@ impl Strings {
         return Ok(Value::String(MutValue::new(StringValue::new(result))));
     }
 
-    pub fn ord(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn ord(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -332,7 +333,7 @@ impl Strings {
         return Ok(Value::Number(NumberValue { n: result as f64 }));
     }
 
-    pub fn chr(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn chr(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -347,7 +348,7 @@ impl Strings {
         ))));
     }
 
-    pub fn as_number(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn as_number(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -363,7 +364,7 @@ impl Strings {
         }
     }
 
-    pub fn split(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn split(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -387,7 +388,7 @@ impl Strings {
         return Ok(Value::List(MutValue::new(ListValue { elements: result })));
     }
 
-    pub fn compare(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn compare(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -420,43 +421,43 @@ impl Strings {
         };
         let to_lower = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::to_lower),
+            callable: Some(Rc::new(Strings::to_lower)),
             bind: false,
             baggage: None,
         });
         let to_upper = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::to_upper),
+            callable: Some(Rc::new(Strings::to_upper)),
             bind: false,
             baggage: None,
         });
         let ord = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::ord),
+            callable: Some(Rc::new(Strings::ord)),
             bind: false,
             baggage: None,
         });
         let chr = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::chr),
+            callable: Some(Rc::new(Strings::chr)),
             bind: false,
             baggage: None,
         });
         let as_number = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::as_number),
+            callable: Some(Rc::new(Strings::as_number)),
             bind: false,
             baggage: None,
         });
         let split = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::split),
+            callable: Some(Rc::new(Strings::split)),
             bind: false,
             baggage: None,
         });
         let compare = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Strings::compare),
+            callable: Some(Rc::new(Strings::compare)),
             bind: false,
             baggage: None,
         });
@@ -484,7 +485,7 @@ impl Strings {
 pub struct Type {}
 
 impl Type {
-    pub fn type_fn(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn type_fn(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -507,7 +508,7 @@ impl Type {
     pub fn build() -> NativeValue {
         let type_fn = NativeValue {
             props: HashMap::new(),
-            callable: Some(&Type::type_fn),
+            callable: Some(Rc::new(Type::type_fn)),
             bind: false,
             baggage: None,
         };
@@ -518,7 +519,7 @@ impl Type {
 pub struct Env {}
 
 impl Env {
-    pub fn get(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn get(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -532,7 +533,7 @@ impl Env {
         return Ok(Value::String(MutValue::new(StringValue::new(result))));
     }
 
-    pub fn set(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn set(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -561,13 +562,13 @@ impl Env {
         };
         let get = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Env::get),
+            callable: Some(Rc::new(Env::get)),
             bind: false,
             baggage: None,
         });
         let set = Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Env::set),
+            callable: Some(Rc::new(Env::set)),
             bind: false,
             baggage: None,
         });
@@ -580,7 +581,7 @@ impl Env {
 pub struct Import {}
 
 impl Import {
-    pub fn import(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn import(vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -590,42 +591,64 @@ impl Import {
                 return Err(ERR_EXPECTED_STRING);
             }
         };
-        let current_abs_path = interpreter::get_absolute_path();
-        let s_ref = string_value.0.borrow();
-        let path = Path::new(&s_ref.s);
-        let full_path = if path.is_absolute() {
-            s_ref.s.clone()
-        } else {
-            let mut path_buf_abs = canonicalize(&current_abs_path).unwrap();
-            path_buf_abs.pop();
-            path_buf_abs.push(s_ref.s.as_str());
-            String::from(path_buf_abs.canonicalize().unwrap().to_string_lossy())
-        };
-        drop(s_ref);
-        let source = match fs::read_to_string(&full_path) {
-            Ok(s) => s,
-            Err(_) => {
-                return Err(RuntimeErr {
-                    msg: "Cannot open file",
-                    signal: None,
-                });
+        let current_abs_path = vm.compiler.file_path.clone();
+        let current_source = vm.compiler.source.clone();
+        let module_path = string_value.0.borrow().s.clone();
+        let path_buf_abs = bundle::resolve(&current_abs_path, &module_path);
+        // Modules bundled into the bytecode are used before looking for files
+        let result = match Self::bundled_module(vm, &path_buf_abs) {
+            Some(context) => {
+                vm.compiler.file_path = String::from(path_buf_abs.to_string_lossy());
+                vm.compiler.source = None;
+                interpreter::import_bundled_module(vm, context)
+            }
+            None => {
+                let (full_path, source) = match path_buf_abs
+                    .canonicalize()
+                    .and_then(|p| fs::read_to_string(&p).map(|s| (p, s)))
+                {
+                    Ok(file) => file,
+                    Err(e) => {
+                        return Err(RuntimeErr::message(
+                            ErrorKind::Import,
+                            format!("Cannot open file {}: {}", module_path, e),
+                        ));
+                    }
+                };
+                vm.compiler.file_path = String::from(full_path.to_string_lossy());
+                interpreter::import_module(vm, source)
             }
         };
-        interpreter::set_absolute_path(full_path);
-        let result = Ok(Value::Native(Rc::new(NativeValue {
-            props: interpreter::import_module(source),
-            callable: None,
-            bind: false,
-            baggage: None,
-        })));
-        interpreter::set_absolute_path(current_abs_path);
-        return result;
+        vm.compiler.file_path = current_abs_path;
+        vm.compiler.source = current_source;
+        match result {
+            Ok(props) => Ok(Value::Native(Rc::new(NativeValue {
+                props,
+                callable: None,
+                bind: false,
+                baggage: None,
+            }))),
+            Err(err) => Err(RuntimeErr::message(
+                ErrorKind::Import,
+                format!("Cannot import module {}", module_path),
+            )
+            .caused_by(err.to_runtime_err())),
+        }
+    }
+
+    fn bundled_module(vm: &VM, path: &Path) -> Option<FnContext> {
+        if vm.compiler.modules.is_empty() {
+            return None;
+        }
+        let main_file = vm.frames.first()?.file.clone()?;
+        let key = bundle::module_key(&bundle::root(&main_file), path)?;
+        vm.compiler.modules.get(&key).cloned()
     }
 
     pub fn build() -> NativeValue {
         let import_mod = NativeValue {
             props: HashMap::new(),
-            callable: Some(&Self::import),
+            callable: Some(Rc::new(Self::import)),
             bind: false,
             baggage: None,
         };
@@ -636,7 +659,7 @@ impl Import {
 pub struct Net {}
 
 impl Net {
-    fn conn_address(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn conn_address(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -651,11 +674,12 @@ impl Net {
             NativeBaggage::TcpSocket(socket) => {
                 socket.peer_addr().unwrap().as_socket().unwrap().to_string()
             }
+            NativeBaggage::Error(_) => return Err(ERR_EXPECTED_OBJECT),
         };
         return Ok(Value::String(MutValue::new(StringValue::new(address_str))));
     }
 
-    fn conn_read(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn conn_read(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -672,6 +696,7 @@ impl Net {
             NativeBaggage::TcpSocket(socket) => {
                 size = socket.read(&mut buf).expect("Read from connection");
             }
+            NativeBaggage::Error(_) => return Err(ERR_EXPECTED_OBJECT),
         };
         let read_from_connection = &buf[0..size];
         return Ok(Value::String(MutValue::new(StringValue::new(
@@ -679,7 +704,7 @@ impl Net {
         ))));
     }
 
-    fn conn_write(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn conn_write(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -701,13 +726,14 @@ impl Net {
             NativeBaggage::TcpSocket(socket) => {
                 socket.write_all(&content).expect("Write to conn");
             }
+            NativeBaggage::Error(_) => return Err(ERR_EXPECTED_OBJECT),
         };
         return Ok(Value::Number(NumberValue {
             n: content.len() as f64,
         }));
     }
 
-    fn address(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn address(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -725,11 +751,12 @@ impl Net {
                 .as_socket()
                 .unwrap()
                 .to_string(),
+            NativeBaggage::Error(_) => return Err(ERR_EXPECTED_OBJECT),
         };
         return Ok(Value::String(MutValue::new(StringValue::new(address_str))));
     }
 
-    fn close(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn close(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -744,11 +771,12 @@ impl Net {
             NativeBaggage::TcpSocket(socket) => {
                 socket.shutdown(Shutdown::Both).unwrap_or(());
             }
+            NativeBaggage::Error(_) => return Err(ERR_EXPECTED_OBJECT),
         }
         return Ok(Value::Nil);
     }
 
-    fn accept(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn accept(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -768,11 +796,11 @@ impl Net {
                     baggage: None,
                 };
                 let baggage = match socket.accept() {
-                    Err(_) => {
-                        return Err(RuntimeErr {
-                            msg: "Cannot accept connection",
-                            signal: None,
-                        });
+                    Err(e) => {
+                        return Err(RuntimeErr::message(
+                            ErrorKind::IO,
+                            format!("Cannot accept connection: {}", e),
+                        ));
                     }
                     Ok((conn, _)) => Some(Rc::new(RefCell::new(NativeBaggage::TcpSocket(conn)))),
                 };
@@ -780,7 +808,7 @@ impl Net {
                     "address".to_string(),
                     Value::Native(Rc::new(NativeValue {
                         props: HashMap::new(),
-                        callable: Some(&Self::conn_address),
+                        callable: Some(Rc::new(Self::conn_address)),
                         bind: true,
                         baggage: baggage.clone(),
                     })),
@@ -789,7 +817,7 @@ impl Net {
                     "close".to_string(),
                     Value::Native(Rc::new(NativeValue {
                         props: HashMap::new(),
-                        callable: Some(&Self::close),
+                        callable: Some(Rc::new(Self::close)),
                         bind: true,
                         baggage: baggage.clone(),
                     })),
@@ -798,7 +826,7 @@ impl Net {
                     "read".to_string(),
                     Value::Native(Rc::new(NativeValue {
                         props: HashMap::new(),
-                        callable: Some(&Self::conn_read),
+                        callable: Some(Rc::new(Self::conn_read)),
                         bind: true,
                         baggage: baggage.clone(),
                     })),
@@ -807,18 +835,19 @@ impl Net {
                     "write".to_string(),
                     Value::Native(Rc::new(NativeValue {
                         props: HashMap::new(),
-                        callable: Some(&Self::conn_write),
+                        callable: Some(Rc::new(Self::conn_write)),
                         bind: true,
                         baggage: baggage.clone(),
                     })),
                 );
                 conn_obj
             }
+            NativeBaggage::Error(_) => return Err(ERR_EXPECTED_OBJECT),
         };
         return Ok(Value::Native(Rc::new(conn)));
     }
 
-    fn listen_tcp(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    fn listen_tcp(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -837,11 +866,11 @@ impl Net {
 
         let socket = match Socket::new(Domain::IPV4, socket2::Type::STREAM, None) {
             Ok(s) => s,
-            Err(_) => {
-                return Err(RuntimeErr {
-                    msg: "Cannot create a new socket",
-                    signal: None,
-                });
+            Err(e) => {
+                return Err(RuntimeErr::message(
+                    ErrorKind::IO,
+                    format!("Cannot create a new socket: {}", e),
+                ));
             }
         };
         let bind_to_address = if string_value.0.borrow().s.starts_with(":") {
@@ -854,28 +883,28 @@ impl Net {
         let mut address = bind_to_address.to_socket_addrs();
         let address = match &mut address {
             Ok(a) => a.next().unwrap(),
-            Err(_) => {
-                return Err(RuntimeErr {
-                    msg: "Cannot parse bind address",
-                    signal: None,
-                });
+            Err(e) => {
+                return Err(RuntimeErr::message(
+                    ErrorKind::Value,
+                    format!("Cannot parse bind address {}: {}", bind_to_address, e),
+                ));
             }
         };
         match socket.bind(&address.into()) {
-            Err(_) => {
-                return Err(RuntimeErr {
-                    msg: "Cannot bind port",
-                    signal: None,
-                })
+            Err(e) => {
+                return Err(RuntimeErr::message(
+                    ErrorKind::IO,
+                    format!("Cannot bind {}: {}", address, e),
+                ))
             }
             _ => {}
         }
         match socket.listen(128) {
-            Err(_) => {
-                return Err(RuntimeErr {
-                    msg: "Cannot listen on port",
-                    signal: None,
-                })
+            Err(e) => {
+                return Err(RuntimeErr::message(
+                    ErrorKind::IO,
+                    format!("Cannot listen on {}: {}", address, e),
+                ))
             }
             _ => {}
         }
@@ -884,7 +913,7 @@ impl Net {
             "address".to_string(),
             Value::Native(Rc::new(NativeValue {
                 props: HashMap::new(),
-                callable: Some(&Self::address),
+                callable: Some(Rc::new(Self::address)),
                 bind: true,
                 baggage: baggage.clone(),
             })),
@@ -893,7 +922,7 @@ impl Net {
             "close".to_string(),
             Value::Native(Rc::new(NativeValue {
                 props: HashMap::new(),
-                callable: Some(&Self::close),
+                callable: Some(Rc::new(Self::close)),
                 bind: true,
                 baggage: baggage.clone(),
             })),
@@ -902,7 +931,7 @@ impl Net {
             "accept".to_string(),
             Value::Native(Rc::new(NativeValue {
                 props: HashMap::new(),
-                callable: Some(&Self::accept),
+                callable: Some(Rc::new(Self::accept)),
                 bind: true,
                 baggage: baggage.clone(),
             })),
@@ -921,7 +950,7 @@ impl Net {
             "listenTcp".to_string(),
             Value::Native(Rc::new(NativeValue {
                 props: HashMap::new(),
-                callable: Some(&Self::listen_tcp),
+                callable: Some(Rc::new(Self::listen_tcp)),
                 bind: false,
                 baggage: None,
             })),
@@ -933,7 +962,7 @@ impl Net {
 pub struct Re {}
 
 impl Re {
-    pub fn regex_find(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn regex_find(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -954,7 +983,7 @@ impl Re {
         return Ok(Value::List(MutValue::new(ListValue{elements: result})));
     }
 
-    pub fn regex_match(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn regex_match(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -983,13 +1012,13 @@ impl Re {
         };
         re.props.insert("find".to_string(), Value::Native(Rc::new(NativeValue{
             props: HashMap::new(),
-            callable: Some(&Self::regex_find),
+            callable: Some(Rc::new(Self::regex_find)),
             bind: false,
             baggage: None,
         })));
         re.props.insert("match".to_string(), Value::Native(Rc::new(NativeValue{
             props: HashMap::new(),
-            callable: Some(&Self::regex_match),
+            callable: Some(Rc::new(Self::regex_match)),
             bind: false,
             baggage: None,
         })));
@@ -1022,7 +1051,7 @@ impl Process {
 pub struct Lists {}
 
 impl Lists {
-    pub fn push(values: Vec<Value>) -> Result<Value, RuntimeErr> {
+    pub fn push(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
         if values.len() != 2 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
@@ -1036,8 +1065,8 @@ impl Lists {
         Ok(Value::List(list_value.clone()))
     }
 
-    pub fn pop(values: Vec<Value>) -> Result<Value, RuntimeErr> {
-        if values.len() != 2 {
+    pub fn pop(_vm: &mut VM, values: Vec<Value>) -> Result<Value, RuntimeErr> {
+        if values.len() != 1 {
             return Err(ERR_INVALID_NUMBER_ARGUMENTS);
         }
         let list_value = match values.first().unwrap() {
@@ -1062,16 +1091,102 @@ impl Lists {
         };
         list.props.insert("push".to_string(), Value::Native(Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Self::push),
+            callable: Some(Rc::new(Self::push)),
             bind: false,
             baggage: None,
         })));
         list.props.insert("pop".to_string(), Value::Native(Rc::new(NativeValue {
             props: HashMap::new(),
-            callable: Some(&Self::pop),
+            callable: Some(Rc::new(Self::pop)),
             bind: false,
             baggage: None,
         })));
         return list;
     }
-}
\ No newline at end of file
+}
+pub struct Assets {}
+
+impl Assets {
+    // Path given to read or exists, asset names use '/' and don't start with "./"
+    fn name(values: &[Value]) -> Result<String, RuntimeErr> {
+        if values.len() != 1 {
+            return Err(ERR_INVALID_NUMBER_ARGUMENTS);
+        }
+        match values.first().unwrap() {
+            Value::String(s) => {
+                let name = s.0.borrow().s.replace('\\', "/");
+                Ok(name.trim_start_matches("./").to_string())
+            }
+            _ => Err(ERR_EXPECTED_STRING),
+        }
+    }
+
+    fn read(files: &BTreeMap<String, Vec<u8>>, values: Vec<Value>) -> Result<Value, RuntimeErr> {
+        let name = Self::name(&values)?;
+        let content = match files.get(&name) {
+            Some(content) => content,
+            None => {
+                return Err(RuntimeErr::message(
+                    ErrorKind::IO,
+                    format!("Asset not found: {}", name),
+                ));
+            }
+        };
+        // Like io.readFile, text is returned as a string and anything else as bytes
+        match String::from_utf8(content.clone()) {
+            Ok(s) => Ok(Value::String(MutValue::new(StringValue::new(s)))),
+            Err(_) => Ok(Value::Bytes(Rc::new(BytesValue { s: content.clone() }))),
+        }
+    }
+
+    fn list(files: &BTreeMap<String, Vec<u8>>, values: Vec<Value>) -> Result<Value, RuntimeErr> {
+        if !values.is_empty() {
+            return Err(ERR_INVALID_NUMBER_ARGUMENTS);
+        }
+        Ok(Value::List(MutValue::new(ListValue {
+            elements: files
+                .keys()
+                .map(|name| Value::String(MutValue::new(StringValue::new(name.clone()))))
+                .collect(),
+        })))
+    }
+
+    fn exists(files: &BTreeMap<String, Vec<u8>>, values: Vec<Value>) -> Result<Value, RuntimeErr> {
+        let name = Self::name(&values)?;
+        Ok(Value::Bool(BoolValue {
+            b: files.contains_key(&name),
+        }))
+    }
+
+    /// Files packed into an embedded executable, keyed by their relative path.
+    pub fn build(files: BTreeMap<String, Vec<u8>>) -> NativeValue {
+        let files = Rc::new(files);
+        let mut assets = NativeValue {
+            props: HashMap::new(),
+            callable: None,
+            bind: false,
+            baggage: None,
+        };
+        let read_files = files.clone();
+        assets.props.insert(
+            "read".to_string(),
+            Value::Native(Rc::new(NativeValue::function(move |_vm, values| {
+                Self::read(&read_files, values)
+            }))),
+        );
+        let list_files = files.clone();
+        assets.props.insert(
+            "list".to_string(),
+            Value::Native(Rc::new(NativeValue::function(move |_vm, values| {
+                Self::list(&list_files, values)
+            }))),
+        );
+        assets.props.insert(
+            "exists".to_string(),
+            Value::Native(Rc::new(NativeValue::function(move |_vm, values| {
+                Self::exists(&files, values)
+            }))),
+        );
+        assets
+    }
+}
@@ -13,6 +13,9 @@ pub struct Parser<'a> {
     pub current: usize,
     pub cls: Vec<CallStack>,
     pub state: &'a mut InterpreterState,
+    // Set after reporting an error, the rest of the statement is skipped
+    // to avoid reporting errors caused by the first one
+    pub panic_mode: bool,
 }
 
 const MAX_FUNCTION_PARAMS: usize = 255;
@@ -23,6 +26,7 @@ impl Parser<'_> {
             current: 0,
             cls: vec![],
             state: state,
+            panic_mode: false,
         };
     }
 
@@ -49,11 +53,11 @@ impl Parser<'_> {
     fn leave_function(&mut self, name: String) {
         let pc = self.get_parsing_context();
         if pc.function != name.to_string() {
-            self.state.fatal_error(InterpreterError {
-                message: "Max number of parameters is 255".to_string(),
-                line: self.peek().line,
-                pos: 0,
-            })
+            self.error(InterpreterError::new(
+                "Max number of parameters is 255".to_string(),
+                self.peek().line,
+                self.peek().column,
+            ));
         }
         self.cls.pop();
     }
@@ -76,6 +80,7 @@ impl Parser<'_> {
         self.cls = vec![];
         self.enter_function("".to_string());
         while !self.is_at_end() {
+            let start = self.current;
             // When multiple empty lines are encountered after a statement
             // the parser founds nil statements, we should avoid them to not
             // break the execution stage
@@ -83,6 +88,10 @@ impl Parser<'_> {
                 None => (),
                 Some(st) => self.state.stmts.push(st),
             }
+            // A stray '}' stops synchronization without being consumed
+            if self.current == start {
+                self.advance();
+            }
         }
         self.leave_function("".to_string());
     }
@@ -126,14 +135,52 @@ impl Parser<'_> {
             return Some(self.advance());
         }
 
-        self.state.set_error(InterpreterError {
-            message: error_message,
-            line: self.peek().line,
-            pos: 0,
-        });
+        self.error(InterpreterError::new(
+            error_message,
+            self.peek().line,
+            self.peek().column,
+        ));
         return None;
     }
 
+    // Only the first error of a statement is reported
+    fn error(&mut self, err: InterpreterError) {
+        if !self.panic_mode {
+            self.state.set_error(err);
+        }
+        self.panic_mode = true;
+    }
+
+    // Skips the rest of a broken statement, up to the end of the line or
+    // the '}' that closes the enclosing block. A body leaves the new line to
+    // the statement that encloses it
+    fn synchronize(&mut self, start: usize, consume_new_line: bool) {
+        let mut depth: i32 = 0;
+        for tk in &self.state.tokens[start..self.current] {
+            match tk.token {
+                Token::LeftCurlyBrace => depth += 1,
+                Token::RightCurlyBrace => depth -= 1,
+                _ => (),
+            }
+        }
+        while !self.is_at_end() {
+            match self.peek().token {
+                Token::Newline if depth <= 0 => {
+                    if consume_new_line {
+                        self.current += 1;
+                    }
+                    break;
+                }
+                Token::RightCurlyBrace if depth <= 0 => break,
+                Token::LeftCurlyBrace => depth += 1,
+                Token::RightCurlyBrace => depth -= 1,
+                _ => (),
+            }
+            self.current += 1;
+        }
+        self.panic_mode = false;
+    }
+
     fn advance(&mut self) -> TokenData {
         if !self.is_at_end() {
             self.current += 1;
@@ -142,17 +189,15 @@ impl Parser<'_> {
     }
 
     fn previous(&mut self) -> TokenData {
-        let mut i = 1;
-        while i <= self.current {
-            if self.state.tokens[self.current - i].token != Token::Newline {
-                break;
-            }
-            i -= 1;
-        }
         return self.state.tokens[self.current - 1].clone();
     }
 
     fn declaration(&mut self, expect_new_line: bool) -> Option<Stmt> {
+        if self.panic_mode {
+            // The enclosing statement is already broken
+            return Some(empty_stmt());
+        }
+        let start = self.current;
         let s = if self.matches(Token::Class) {
             self.class()
         } else if self.matches(Token::Fn) {
@@ -162,8 +207,16 @@ impl Parser<'_> {
         } else {
             Some(self.statement())
         };
-        if expect_new_line {
+        if self.panic_mode {
+            self.synchronize(start, expect_new_line);
+            return Some(empty_stmt());
+        }
+        // Trailing empty lines leave the last statement at the end of file
+        if expect_new_line && !self.is_at_end() {
             self.consume(Token::Newline, "Expected new line".to_string());
+            if self.panic_mode {
+                self.synchronize(start, true);
+            }
         }
         return s;
     }
@@ -185,7 +238,7 @@ impl Parser<'_> {
 
         let mut methods: Vec<FnStmt> = vec![];
         let mut static_methods: Vec<FnStmt> = vec![];
-        while !self.check(Token::RightCurlyBrace) && !self.is_at_end() {
+        while !self.panic_mode && !self.check(Token::RightCurlyBrace) && !self.is_at_end() {
             if self.matches(Token::Class) {
                 if let Stmt::Fn(fn_stmt) = self.fn_stmt() {
                     static_methods.push(fn_stmt);
@@ -217,7 +270,7 @@ impl Parser<'_> {
     fn fn_stmt(&mut self) -> Stmt {
         let name = self
             .consume(Token::Identifier, "Expected function name".to_string())
-            .unwrap();
+            .unwrap_or_else(|| self.peek());
 
         self.enter_function(name.lexeme.clone());
 
@@ -230,15 +283,15 @@ impl Parser<'_> {
         if !self.check(Token::RightParen) {
             loop {
                 if params.len() > MAX_FUNCTION_PARAMS {
-                    self.state.fatal_error(InterpreterError {
-                        message: "Max number of parameters is 255".to_string(),
-                        line: self.peek().line,
-                        pos: 0,
-                    });
+                    self.error(InterpreterError::new(
+                        "Max number of parameters is 255".to_string(),
+                        self.peek().line,
+                        self.peek().column,
+                    ));
                 }
                 params.push(
                     self.consume(Token::Identifier, "Expected function parameter".to_string())
-                        .unwrap(),
+                        .unwrap_or_else(|| self.peek()),
                 );
                 if !self.matches(Token::Comma) {
                     break;
@@ -276,15 +329,15 @@ impl Parser<'_> {
         if !self.check(Token::RightParen) {
             loop {
                 if params.len() > MAX_FUNCTION_PARAMS {
-                    self.state.fatal_error(InterpreterError {
-                        message: "Max number of parameters is 255".to_string(),
-                        line: self.peek().line,
-                        pos: 0,
-                    });
+                    self.error(InterpreterError::new(
+                        "Max number of parameters is 255".to_string(),
+                        self.peek().line,
+                        self.peek().column,
+                    ));
                 }
                 params.push(
                     self.consume(Token::Identifier, "Expect function parameter".to_string())
-                        .unwrap(),
+                        .unwrap_or_else(|| self.peek()),
                 );
                 if !self.matches(Token::Comma) {
                     break;
@@ -311,7 +364,7 @@ impl Parser<'_> {
     fn let_stmt(&mut self) -> Stmt {
         let name = self
             .consume(Token::Identifier, "Expected variable name".to_string())
-            .unwrap();
+            .unwrap_or_else(|| self.peek());
 
         let init: Option<Expr> = if self.matches(Token::Equal) {
             Some(self.expression())
@@ -332,6 +385,9 @@ impl Parser<'_> {
         if self.matches(Token::Try) {
             return self.try_catch();
         }
+        if self.matches(Token::Throw) {
+            return self.throw();
+        }
         if self.matches(Token::If) {
             return self.if_stmt();
         }
@@ -372,11 +428,12 @@ impl Parser<'_> {
             self.consume(Token::Semicolon, "Expected semicolon".to_string());
             Some(Box::new(aux))
         } else {
-            self.state.set_error(InterpreterError {
-                message: "Empty expression or let was expected at this position".to_string(),
-                line: self.peek().line,
-                pos: 0,
-            });
+            // The rest of the loop can still be parsed, so keep reporting errors
+            self.state.set_error(InterpreterError::new(
+                "Empty expression or let was expected at this position".to_string(),
+                self.peek().line,
+                self.peek().column,
+            ));
             None
         };
 
@@ -385,7 +442,7 @@ impl Parser<'_> {
 
         let inc = self.expression();
 
-        let body = self.declaration(false);
+        let body = self.body();
 
         self.leave_loop();
 
@@ -394,7 +451,7 @@ impl Parser<'_> {
             initializer: init,
             condition: cond,
             increment: inc,
-            body: Box::new(body.unwrap()),
+            body: Box::new(body),
         });
     }
 
@@ -406,7 +463,7 @@ impl Parser<'_> {
         }
         self.consume(Token::In, "Expected 'in'".to_string());
         let collection: Expr = self.expression();
-        let body = self.declaration(false).unwrap();
+        let body = self.body();
         return Stmt::EnhancedFor(EnhancedForStmt {
             keyword: keyword,
             identifiers: ids,
@@ -416,23 +473,38 @@ impl Parser<'_> {
     }
 
     fn try_catch(&mut self) -> Stmt {
-        let try_body = self.declaration(false).unwrap();
-        self.consume(
-            Token::Catch,
-            "A catch block was expected at this position".to_string(),
-        );
-        let name = self
-            .consume(Token::Identifier, "Expected variable name".to_string())
-            .unwrap();
-        let catch_body = self.declaration(false).unwrap();
+        let try_body = self.body();
+        // The catch block can be left out when there is a finally block
+        let (name, catch_body) = if self.check(Token::Finally) {
+            (None, None)
+        } else {
+            self.consume(
+                Token::Catch,
+                "A catch block was expected at this position".to_string(),
+            );
+            let name = self.consume(Token::Identifier, "Expected variable name".to_string());
+            (name, Some(Box::new(self.body())))
+        };
+        let finally_body = if self.matches(Token::Finally) {
+            Some(Box::new(self.body()))
+        } else {
+            None
+        };
 
         return Stmt::TryCatch(TryCatchStmt {
             try_body: Box::new(try_body),
             name: name,
-            catch_body: Box::new(catch_body),
+            catch_body,
+            finally_body,
         });
     }
 
+    fn throw(&mut self) -> Stmt {
+        let keyword = self.previous();
+        let value = self.expression();
+        Stmt::Throw(ThrowStmt { keyword, value })
+    }
+
     fn if_stmt(&mut self) -> Stmt {
         let keyword = self.previous();
         let condition = self.expression();
@@ -442,10 +514,7 @@ impl Parser<'_> {
             "Expected '{' at this position".to_string(),
         );
 
-        let mut then_branch: Vec<Stmt> = vec![];
-        while !self.matches(Token::RightCurlyBrace) {
-            then_branch.push(self.declaration(false).unwrap());
-        }
+        let then_branch = self.block();
 
         let mut elif_branches: Vec<ElifBranch> = vec![];
         while self.matches(Token::Elif) {
@@ -454,10 +523,7 @@ impl Parser<'_> {
                 Token::LeftCurlyBrace,
                 "Expected '{' at this position".to_string(),
             );
-            let mut then_branch: Vec<Stmt> = vec![];
-            while !self.matches(Token::RightCurlyBrace) {
-                then_branch.push(self.declaration(false).unwrap());
-            }
+            let then_branch = self.block();
             let elif = ElifBranch {
                 condition: condition,
                 then_branch: then_branch,
@@ -471,13 +537,7 @@ impl Parser<'_> {
                 Token::LeftCurlyBrace,
                 "Expected '{' at this position".to_string(),
             );
-            while !self.check(Token::RightCurlyBrace) {
-                else_branch.push(self.declaration(false).unwrap());
-            }
-            self.consume(
-                Token::RightCurlyBrace,
-                "Expected '}' at this position".to_string(),
-            );
+            else_branch = self.block();
         }
 
         let st = IfStmt {
@@ -508,11 +568,11 @@ impl Parser<'_> {
     fn brk(&mut self) -> Stmt {
         let keyword = self.previous();
         if !self.inside_loop() {
-            self.state.set_error(InterpreterError {
-                message: "Statement only allowed for use inside loop".to_string(),
-                line: keyword.line,
-                pos: 0,
-            });
+            self.error(InterpreterError::new(
+                "Statement only allowed for use inside loop".to_string(),
+                keyword.line,
+                keyword.column,
+            ));
         }
         let brk_stmt = BreakStmt { keyword };
         return Stmt::Break(brk_stmt);
@@ -521,11 +581,11 @@ impl Parser<'_> {
     fn cont(&mut self) -> Stmt {
         let keyword = self.previous();
         if !self.inside_loop() {
-            self.state.set_error(InterpreterError {
-                message: "Statement only allowed for use inside loop".to_string(),
-                line: keyword.line,
-                pos: 0,
-            });
+            self.error(InterpreterError::new(
+                "Statement only allowed for use inside loop".to_string(),
+                keyword.line,
+                keyword.column,
+            ));
         }
         let continue_stmt = ContinueStmt { keyword };
         return Stmt::Continue(continue_stmt);
@@ -535,7 +595,7 @@ impl Parser<'_> {
         let keyword = self.previous();
         self.enter_loop();
         let condition = self.expression();
-        let body = self.declaration(false).unwrap();
+        let body = self.body();
         self.leave_loop();
         let while_stmt = WhileStmt {
             keyword,
@@ -547,12 +607,20 @@ impl Parser<'_> {
 
     fn block(&mut self) -> Vec<Stmt> {
         let mut stmts: Vec<Stmt> = vec![];
-        while !self.matches(Token::RightCurlyBrace) {
-            stmts.push(self.declaration(false).unwrap());
+        while !self.panic_mode && !self.check(Token::RightCurlyBrace) && !self.is_at_end() {
+            stmts.push(self.body());
         }
+        self.consume(
+            Token::RightCurlyBrace,
+            "Expected '}' at this position".to_string(),
+        );
         return stmts;
     }
 
+    fn body(&mut self) -> Stmt {
+        self.declaration(false).unwrap_or_else(empty_stmt)
+    }
+
     fn expression_stmt(&mut self) -> Stmt {
         let expr = self.expression();
         if expr != Expr::Empty {
@@ -578,7 +646,7 @@ impl Parser<'_> {
         let elements = self.arguments(Token::RightBrace);
         let brace = self
             .consume(Token::RightBrace, "Expected ']' at end of list".to_string())
-            .unwrap();
+            .unwrap_or_else(|| self.peek());
         let list_expr = ListExpr { elements, brace };
         return Expr::List(list_expr);
     }
@@ -590,7 +658,7 @@ impl Parser<'_> {
                 Token::RightCurlyBrace,
                 "Expected '}' at the end of dict".to_string(),
             )
-            .unwrap();
+            .unwrap_or_else(|| self.peek());
         let dict_expr = DictionaryExpr {
             elements: elements,
             curly_brace: curly_brace,
@@ -616,9 +684,22 @@ impl Parser<'_> {
     }
 
     fn assignment(&mut self) -> Expr {
-        let mut expr = self.or();
-        if self.matches(Token::Equal) {
+        let mut expr = self.conditional();
+        if self.matches_many(vec![
+            Token::Equal,
+            Token::PlusEqual,
+            Token::MinusEqual,
+            Token::StarEqual,
+            Token::SlashEqual,
+            Token::ModEqual,
+            Token::PowerEqual,
+        ]) {
             let equal = self.previous();
+            let operator = if equal.token == Token::Equal {
+                None
+            } else {
+                Some(equal.clone())
+            };
             let value = self.assignment();
 
             let access = match expr {
@@ -643,6 +724,7 @@ impl Parser<'_> {
                         name: variable.name.unwrap(),
                         value: Box::new(value),
                         access: access,
+                        operator,
                     };
                     return Expr::Assign(assign);
                 }
@@ -652,19 +734,41 @@ impl Parser<'_> {
                         value: Box::new(value),
                         object: get.object,
                         access: access,
+                        operator,
                     };
                     return Expr::Set(set);
                 }
-                _ => self.state.fatal_error(InterpreterError {
-                    message: "Undefined statement".to_string(),
-                    line: equal.line,
-                    pos: 0,
-                }),
+                _ => self.error(InterpreterError::new(
+                    "Undefined statement".to_string(),
+                    equal.line,
+                    equal.column,
+                )),
             }
         }
         return expr;
     }
 
+    fn conditional(&mut self) -> Expr {
+        let expr = self.or();
+        if self.matches(Token::Question) {
+            let question = self.previous();
+            let then_branch = self.expression();
+            self.consume(
+                Token::Colon,
+                "Expected ':' after then branch of conditional expression".to_string(),
+            );
+            let else_branch = self.conditional();
+            let conditional_expr = ConditionalExpr {
+                condition: Box::new(expr),
+                question,
+                then_branch: Box::new(then_branch),
+                else_branch: Box::new(else_branch),
+            };
+            return Expr::Conditional(conditional_expr);
+        }
+        expr
+    }
+
     fn access(&mut self, object: Expr) -> Expr {
         let mut slice = AccessExpr {
             first: Box::new(Expr::Empty),
@@ -673,6 +777,7 @@ impl Parser<'_> {
                 lexeme: "".to_string(),
                 literal: None,
                 line: 0,
+                column: 0,
             },
             object: Box::new(object),
             brace: self.previous(),
@@ -682,6 +787,7 @@ impl Parser<'_> {
                 lexeme: "".to_string(),
                 literal: None,
                 line: 0,
+                column: 0,
             },
             third: Box::new(Expr::Empty),
         };
@@ -860,7 +966,7 @@ impl Parser<'_> {
                         Token::Identifier,
                         "Expected property name after '.'".to_string(),
                     )
-                    .unwrap();
+                    .unwrap_or_else(|| self.peek());
                 let get_expr = GetExpr {
                     object: Box::new(expr),
                     name: name,
@@ -879,7 +985,7 @@ impl Parser<'_> {
         let arguments = self.arguments(Token::RightParen);
         let paren = self
             .consume(Token::RightParen, "Expect ')' after arguments".to_string())
-            .unwrap();
+            .unwrap_or_else(|| self.peek());
         let call_expr = CallExpr {
             callee: Box::new(callee),
             arguments: arguments,
@@ -893,11 +999,11 @@ impl Parser<'_> {
         if !self.check(token_type) {
             loop {
                 if token_type == Token::RightParen && arguments.len() >= MAX_FUNCTION_PARAMS {
-                    self.state.fatal_error(InterpreterError {
-                        message: "Max number of arguments is 255".to_string(),
-                        line: self.peek().line,
-                        pos: 0,
-                    });
+                    self.error(InterpreterError::new(
+                        "Max number of arguments is 255".to_string(),
+                        self.peek().line,
+                        self.peek().column,
+                    ));
                 }
                 arguments.push(self.expression());
                 if !self.matches(Token::Comma) || self.check(token_type) {
@@ -915,6 +1021,9 @@ impl Parser<'_> {
             };
             return Expr::Literal(lit_expr);
         }
+        if self.matches(Token::FStringStart) {
+            return self.fstring();
+        }
         if self.matches(Token::False) {
             let lit_expr = LiteralExpr {
                 value: Literal::Boolean(false),
@@ -969,14 +1078,41 @@ impl Parser<'_> {
             return Expr::Empty;
         }
 
-        self.state.fatal_error(InterpreterError {
-            message: "Undefined expression".to_string(),
-            line: self.peek().line,
-            pos: 0,
-        });
+        self.error(InterpreterError::new(
+            "Undefined expression".to_string(),
+            self.peek().line,
+            self.peek().column,
+        ));
         return Expr::Empty;
     }
 
+    fn fstring(&mut self) -> Expr {
+        let token = self.previous();
+        let mut parts: Vec<Expr> = vec![];
+        let mut text = token.clone();
+        loop {
+            // Empty text between expressions adds nothing to the result
+            if text.literal != Some(Literal::String("".to_string())) {
+                parts.push(Expr::Literal(LiteralExpr {
+                    value: text.literal.unwrap(),
+                }));
+            }
+            if self.check(Token::FStringEnd) || self.is_at_end() {
+                break;
+            }
+            parts.push(self.expression());
+            match self.consume(
+                Token::FStringMiddle,
+                "Expected '}' after expression in f-string".to_string(),
+            ) {
+                Some(tk) => text = tk,
+                None => break,
+            }
+        }
+        self.consume(Token::FStringEnd, "Expected '\"' at end of f-string".to_string());
+        Expr::FString(FStringExpr { parts, token })
+    }
+
     fn super_expr(&mut self) -> Expr {
         let keyword = self.previous();
         let method: TokenData;
@@ -987,12 +1123,13 @@ impl Parser<'_> {
             );
             method = self
                 .consume(Token::Identifier, "Expected variable name".to_string())
-                .unwrap();
+                .unwrap_or_else(|| self.peek());
         } else {
             method = TokenData {
                 token: Token::Identifier,
                 lexeme: "init".to_string(),
                 line: keyword.line,
+                column: keyword.column,
                 literal: None,
             };
         }
@@ -1003,3 +1140,83 @@ impl Parser<'_> {
         return Expr::Super(super_expr);
     }
 }
+
+// Placeholder for statements that could not be parsed
+fn empty_stmt() -> Stmt {
+    Stmt::Expr(ExprStmt {
+        expression: Expr::Empty,
+        last: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(source: &str) -> InterpreterState {
+        let mut state = InterpreterState::new("test.gr".to_string(), source.to_string());
+        Lexer::new(&mut state).scan();
+        Parser::new(&mut state).parse();
+        state
+    }
+
+    #[test]
+    fn test_fstring() {
+        let state = parse("let a = f\"x {a} y {f\"{a}\"}\"");
+        assert!(state.errors.is_empty());
+        match &state.stmts[0] {
+            Stmt::Let(LetStmt { initializer: Some(Expr::FString(fstring)), .. }) => {
+                assert_eq!(fstring.parts.len(), 4)
+            }
+            stmt => panic!("expected an f-string, found {:?}", stmt),
+        }
+
+        let state = parse("f\"a {1 2} b\"");
+        assert_eq!(state.errors[0].message, "Expected '}' after expression in f-string");
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let state = parse("a += 1\na.b[0] ^= 2");
+        assert!(state.errors.is_empty());
+        let operators: Vec<Option<Token>> = state
+            .stmts
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Expr(ExprStmt { expression: Expr::Assign(assign), .. }) => {
+                    assign.operator.as_ref().map(|op| op.token)
+                }
+                Stmt::Expr(ExprStmt { expression: Expr::Set(set), .. }) => {
+                    assert!(set.access.is_some());
+                    set.operator.as_ref().map(|op| op.token)
+                }
+                stmt => panic!("expected an assignment, found {:?}", stmt),
+            })
+            .collect();
+        assert_eq!(operators, vec![Some(Token::PlusEqual), Some(Token::PowerEqual)]);
+
+        let state = parse("1 -= 2");
+        assert_eq!(state.errors[0].message, "Undefined statement");
+    }
+
+    #[test]
+    fn test_conditional_expression() {
+        // Right associative, the else branch holds the nested conditional
+        let state = parse("let a = b ? c : d ? e : f");
+        assert!(state.errors.is_empty());
+        match &state.stmts[0] {
+            Stmt::Let(LetStmt { initializer: Some(Expr::Conditional(conditional)), .. }) => {
+                assert!(matches!(*conditional.then_branch, Expr::Var(_)));
+                assert!(matches!(*conditional.else_branch, Expr::Conditional(_)));
+            }
+            stmt => panic!("expected a conditional expression, found {:?}", stmt),
+        }
+
+        let state = parse("let a = b ? c");
+        assert_eq!(
+            state.errors[0].message,
+            "Expected ':' after then branch of conditional expression"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::interpreter::{self, Runtime};
+use crate::lexer::Lexer;
+use crate::state::InterpreterState;
+use crate::token::Token;
+use crate::value::Value;
+
+const PROMPT: &str = ">>> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+/// Reads statements from the terminal and runs them one by one on the same runtime,
+/// so that globals are kept between entries. Expression results are echoed.
+pub fn start() {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            println!("Cannot start the REPL: {}", err);
+            return;
+        }
+    };
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+
+    let mut runtime = Runtime::new("<repl>".to_string());
+    let mut source = String::new();
+    loop {
+        let prompt = if source.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                source.push_str(&line);
+                source.push('\n');
+                if !is_complete(&source) {
+                    continue;
+                }
+                let entry = std::mem::take(&mut source);
+                if entry.trim().is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(entry.trim_end());
+                match runtime.evaluate(entry) {
+                    Ok(Value::Nil) => (),
+                    Ok(value) => println!("{}", value.repr()),
+                    Err(err) => interpreter::print_error(&err),
+                }
+            }
+            // Ctrl-C drops the entry being typed
+            Err(ReadlineError::Interrupted) => source.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                println!("{}", err);
+                break;
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".grotsky_history"))
+}
+
+// An entry is complete once all braces, brackets, parens and triple quoted strings
+// are closed. Input that doesn't lex otherwise is complete, so that the error gets reported.
+fn is_complete(source: &str) -> bool {
+    let state = &mut InterpreterState::new("<repl>".to_string(), source.to_string());
+    let mut lexer = Lexer::new(state);
+    lexer.scan();
+    if !state.errors.is_empty() {
+        return !state
+            .errors
+            .iter()
+            .any(|err| err.message == "Closing \"\"\" was expected");
+    }
+    let mut depth = 0;
+    for tk in &state.tokens {
+        match tk.token {
+            Token::LeftCurlyBrace | Token::LeftBrace | Token::LeftParen => depth += 1,
+            Token::RightCurlyBrace | Token::RightBrace | Token::RightParen => depth -= 1,
+            _ => (),
+        }
+    }
+    depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("let a = 1\n"));
+        assert!(!is_complete("fn f() {\n"));
+        assert!(!is_complete("let l = [1,\n"));
+        assert!(is_complete("fn f() {\n return 1\n}\n"));
+        assert!(is_complete("let s = \"{\"\n"));
+        assert!(!is_complete("let s = \"\"\"\n  a\n"));
+        assert!(is_complete("let s = \"\"\"\n  a\n  \"\"\"\n"));
+    }
+}
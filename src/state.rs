@@ -1,3 +1,4 @@
+use crate::errors::source_line;
 use crate::stmt::*;
 use crate::token::*;
 
@@ -5,12 +6,27 @@ use crate::token::*;
 pub struct InterpreterError {
     pub message: String,
     pub line: i32,
-    #[allow(dead_code)]
-    pub pos: usize,
+    pub column: i32,
+    // Filled in when the error is reported
+    pub file: String,
+    pub source_line: Option<String>,
+}
+
+impl InterpreterError {
+    pub fn new(message: String, line: i32, column: i32) -> Self {
+        InterpreterError {
+            message,
+            file: String::new(),
+            line,
+            column,
+            source_line: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct InterpreterState {
+    pub file_path: String,
     pub source: String,
     pub tokens: Vec<TokenData>,
     pub errors: Vec<InterpreterError>,
@@ -18,21 +34,19 @@ pub struct InterpreterState {
 }
 
 impl InterpreterState {
-    pub fn new(source: String) -> Self {
+    pub fn new(file_path: String, source: String) -> Self {
         InterpreterState {
-            source: source,
+            file_path,
+            source,
             tokens: vec![],
             errors: vec![],
             stmts: vec![],
         }
     }
 
-    pub fn set_error(&mut self, err: InterpreterError) {
-        self.errors.push(err);
-    }
-
-    pub fn fatal_error(&mut self, err: InterpreterError) {
+    pub fn set_error(&mut self, mut err: InterpreterError) {
+        err.file = self.file_path.clone();
+        err.source_line = source_line(&self.source, err.line);
         self.errors.push(err);
-        panic!();
     }
 }
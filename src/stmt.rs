@@ -27,8 +27,15 @@ pub struct EnhancedForStmt {
 #[derive(Debug, PartialEq, Clone)]
 pub struct TryCatchStmt {
     pub try_body: Box<Stmt>,
-    pub name: TokenData,
-    pub catch_body: Box<Stmt>,
+    pub name: Option<TokenData>,
+    pub catch_body: Option<Box<Stmt>>,
+    pub finally_body: Option<Box<Stmt>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ThrowStmt {
+    pub keyword: TokenData,
+    pub value: Expr,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -109,12 +116,14 @@ pub enum Stmt {
     Return(ReturnStmt),
     Break(BreakStmt),
     TryCatch(TryCatchStmt),
+    Throw(ThrowStmt),
     Expr(ExprStmt),
 }
 
 pub trait StmtVisitor<T> {
     fn visit_expr_stmt(&mut self, stmt: &ExprStmt) -> T;
     fn visit_try_catch_stmt(&mut self, stmt: &TryCatchStmt) -> T;
+    fn visit_throw_stmt(&mut self, stmt: &ThrowStmt) -> T;
     fn visit_classic_for_stmt(&mut self, stmt: &ClassicForStmt) -> T;
     fn visit_enhanced_for_stmt(&mut self, stmt: &EnhancedForStmt) -> T;
     fn visit_let_stmt(&mut self, stmt: &LetStmt) -> T;
@@ -22,7 +22,7 @@ pub enum Token {
     EOF,
     Newline,
     // Single-character tokens.
-    // (, ), [, ], {, } ',', ., -, +, ;, /, %, *, ^, :, ;
+    // (, ), [, ], {, } ',', ., -, +, ;, /, %, *, ^, :, ;, ?
     LeftParen,
     RightParen,
     LeftBrace,
@@ -39,6 +39,7 @@ pub enum Token {
     Power,
     Colon,
     Semicolon,
+    Question,
     // One or two character tokens.
     // !=, =, ==, >, >=, <, <=
     BangEqual,
@@ -48,11 +49,24 @@ pub enum Token {
     GreaterEqual,
     Less,
     LessEqual,
+    // Compound assignment.
+    // +=, -=, *=, /=, %=, ^=
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    ModEqual,
+    PowerEqual,
     // Literals.
     // *variable*, string, int,
     Identifier,
     String,
     Number,
+    // Interpolated strings: f"a {x} b" is lexed as FStringStart("a "),
+    // the tokens of x, FStringMiddle(" b") and FStringEnd
+    FStringStart,
+    FStringMiddle,
+    FStringEnd,
     // Keywords.
     // and, class, else, false, fn, for, if, elif, nil, or,
     // return, break, continue, super, this, true, let, while, not, in, begin, end
@@ -78,6 +92,8 @@ pub enum Token {
     In,
     Try,
     Catch,
+    Finally,
+    Throw,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -94,6 +110,7 @@ pub struct TokenData {
     pub lexeme: String,
     pub literal: Option<Literal>,
     pub line: i32,
+    pub column: i32,
 }
 
 #[cfg(test)]
@@ -121,6 +138,7 @@ mod tests {
             lexeme: "abc".to_string(),
             literal: None,
             line: 1,
+            column: 1,
         };
         let debug_str = format!("{:?}", td);
         assert!(debug_str.contains("Identifier"));
@@ -10,6 +10,7 @@ use crate::errors::{
     ERR_UNDEFINED_OP, ERR_UNDEFINED_OPERATOR, ERR_UNDEFINED_PROP, ERR_EXPECTED_INDEX,
 };
 use crate::token::Literal;
+use crate::vm::VM;
 
 #[derive(Debug, Clone)]
 pub struct MutValue<T>(pub Rc<RefCell<T>>);
@@ -139,6 +140,8 @@ impl Value {
             Value::Native(n) => {
                 if n.callable.is_some() {
                     "<fn native>".to_string()
+                } else if let Some(e) = n.error() {
+                    e.msg.to_string()
                 } else {
                     "<instance native>".to_string()
                 }
@@ -329,52 +332,52 @@ impl Value {
         }
         return Err(ERR_UNDEFINED_OP);
     }
-    pub fn div(&self, other: &Value) -> Value {
+    pub fn div(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
             if let Value::Number(other_val) = other {
-                return Value::Number(NumberValue {
+                return Ok(Value::Number(NumberValue {
                     n: num_val.n / other_val.n,
-                });
+                }));
             }
         }
-        panic!("Not implemented");
+        return Err(ERR_UNDEFINED_OP);
     }
-    pub fn pow(&self, other: &Value) -> Value {
+    pub fn pow(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
             if let Value::Number(other_val) = other {
-                return Value::Number(NumberValue {
+                return Ok(Value::Number(NumberValue {
                     n: num_val.n.powf(other_val.n),
-                });
+                }));
             }
         }
-        panic!("Not implemented");
+        return Err(ERR_UNDEFINED_OP);
     }
-    pub fn modulo(&self, other: &Value) -> Value {
+    pub fn modulo(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
             if let Value::Number(other_val) = other {
-                return Value::Number(NumberValue {
+                return Ok(Value::Number(NumberValue {
                     n: num_val.n % other_val.n,
-                });
+                }));
             }
         }
-        panic!("Not implemented");
+        return Err(ERR_UNDEFINED_OP);
     }
-    pub fn lt(&self, other: &Value) -> Value {
+    pub fn lt(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
             if let Value::Number(other_val) = other {
-                return Value::Bool(BoolValue {
+                return Ok(Value::Bool(BoolValue {
                     b: num_val.n < other_val.n,
-                });
+                }));
             }
         }
         if let Value::String(str_val) = self {
             if let Value::String(other_val) = other {
-                return Value::Bool(BoolValue {
+                return Ok(Value::Bool(BoolValue {
                     b: str_val.0.borrow().s < other_val.0.borrow().s,
-                });
+                }));
             }
         }
-        panic!("Not implemented");
+        return Err(ERR_UNDEFINED_OP);
     }
     pub fn lte(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
@@ -393,39 +396,39 @@ impl Value {
         }
         return Err(ERR_UNDEFINED_OP);
     }
-    pub fn gt(&self, other: &Value) -> Value {
+    pub fn gt(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
             if let Value::Number(other_val) = other {
-                return Value::Bool(BoolValue {
+                return Ok(Value::Bool(BoolValue {
                     b: num_val.n > other_val.n,
-                });
+                }));
             }
         }
         if let Value::String(str_val) = self {
             if let Value::String(other_val) = other {
-                return Value::Bool(BoolValue {
+                return Ok(Value::Bool(BoolValue {
                     b: str_val.0.borrow().s > other_val.0.borrow().s,
-                });
+                }));
             }
         }
-        panic!("Not implemented");
+        return Err(ERR_UNDEFINED_OP);
     }
-    pub fn gte(&self, other: &Value) -> Value {
+    pub fn gte(&self, other: &Value) -> Result<Value, RuntimeErr> {
         if let Value::Number(num_val) = self {
             if let Value::Number(other_val) = other {
-                return Value::Bool(BoolValue {
+                return Ok(Value::Bool(BoolValue {
                     b: num_val.n >= other_val.n,
-                });
+                }));
             }
         }
         if let Value::String(str_val) = self {
             if let Value::String(other_val) = other {
-                return Value::Bool(BoolValue {
+                return Ok(Value::Bool(BoolValue {
                     b: str_val.0.borrow().s >= other_val.0.borrow().s,
-                });
+                }));
             }
         }
-        panic!("Not implemented");
+        return Err(ERR_UNDEFINED_OP);
     }
     pub fn equal(&self, other: &Value) -> Value {
         if let Value::Number(num_val) = self {
@@ -570,7 +573,7 @@ pub struct BoolValue {
 
 #[derive(Debug, Clone)]
 pub struct FnValue {
-    pub prototype: u16,
+    pub prototype: u32,
     pub upvalues: Vec<MutValue<Value>>,
     pub constants: Vec<Value>,
     pub this: Option<MutValue<ObjectValue>>,
@@ -666,7 +669,7 @@ impl StringValue {
                     Err(e) => Err(e),
                 }
             }
-            _ => unimplemented!(),
+            _ => Err(ERR_ONLY_NUMBERS),
         }
     }
 }
@@ -736,7 +739,7 @@ impl ListValue {
                     Err(e) => Err(e),
                 }
             }
-            _ => unimplemented!(),
+            _ => Err(ERR_ONLY_NUMBERS),
         }
     }
 }
@@ -803,14 +806,50 @@ impl SliceValue {
     }
 }
 
+/// Signature of functions implemented in Rust and callable from Grotsky.
+pub type NativeFn = dyn Fn(&mut VM, Vec<Value>) -> Result<Value, RuntimeErr>;
+
 #[derive(Clone)]
 pub struct NativeValue {
     pub props: HashMap<String, Value>,
-    pub callable: Option<&'static dyn Fn(Vec<Value>) -> Result<Value, RuntimeErr>>,
+    pub callable: Option<Rc<NativeFn>>,
     pub bind: bool,
     pub baggage: Option<Rc<RefCell<NativeBaggage>>>,
 }
 
+impl NativeValue {
+    /// Wraps a Rust function or closure so that it can be called from Grotsky.
+    pub fn function<F>(f: F) -> NativeValue
+    where
+        F: Fn(&mut VM, Vec<Value>) -> Result<Value, RuntimeErr> + 'static,
+    {
+        NativeValue {
+            props: HashMap::new(),
+            callable: Some(Rc::new(f)),
+            bind: false,
+            baggage: None,
+        }
+    }
+
+    /// Error object received by a catch block, see `RuntimeErr::to_value`.
+    pub fn error(&self) -> Option<RuntimeErr> {
+        match &*self.baggage.as_ref()?.borrow() {
+            NativeBaggage::Error(e) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    /// Module whose properties are accessed with the dot operator, e.g. `io.println`.
+    pub fn module(props: HashMap<String, Value>) -> NativeValue {
+        NativeValue {
+            props,
+            callable: None,
+            bind: false,
+            baggage: None,
+        }
+    }
+}
+
 impl core::fmt::Debug for NativeValue {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         if self.callable.is_some() {
@@ -824,6 +863,8 @@ impl core::fmt::Debug for NativeValue {
 #[derive(Debug)]
 pub enum NativeBaggage {
     TcpSocket(socket2::Socket),
+    // Error handed to a catch block, kept to be able to throw it again
+    Error(RuntimeErr),
 }
 
 #[derive(Debug, Clone)]
@@ -861,7 +902,7 @@ impl BytesValue {
                     Err(e) => Err(e),
                 }
             }
-            _ => unimplemented!(),
+            _ => Err(ERR_ONLY_NUMBERS),
         }
     }
 }
@@ -0,0 +1,317 @@
+use crate::compiler::{Compiler, UpvalueRef};
+use crate::errors::Error;
+use crate::instruction::{Instruction, OpCode};
+
+/// Checks that compiled code only refers to registers, constants, prototypes,
+/// upvalues and jump targets that exist, so that the VM can index them blindly.
+/// Meant for bytecode loaded from files, the compiler always produces valid code.
+pub fn verify(compiler: &Compiler) -> Result<(), Error> {
+    let top_level = compiler
+        .contexts
+        .last()
+        .map(|main| ("<main>".to_string(), main))
+        .into_iter()
+        .chain(compiler.modules.iter().map(|(key, m)| (format!("module {}", key), m)));
+    for (name, context) in top_level {
+        let instructions: Vec<Instruction> = context
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.instructions.iter().map(|i| i.inst.clone()))
+            .collect();
+        let function = Function {
+            name: &name,
+            instructions: &instructions,
+            register_count: context.register_count,
+            upvalue_count: 0,
+            is_main: true,
+        };
+        function.verify(compiler)?;
+    }
+    for prototype in &compiler.prototypes {
+        let function = Function {
+            name: &prototype.name,
+            instructions: &prototype.instructions,
+            register_count: prototype.register_count,
+            upvalue_count: prototype.upvalues.len(),
+            is_main: false,
+        };
+        if prototype.instruction_data.len() != prototype.instructions.len() {
+            return Err(function.error(0, "source information doesn't match the code".to_string()));
+        }
+        // Arguments are copied into the registers following the function itself
+        if prototype.param_count > 0 && prototype.param_count >= prototype.register_count as usize {
+            return Err(function.error(
+                0,
+                format!(
+                    "{} parameters don't fit in {} registers",
+                    prototype.param_count, prototype.register_count
+                ),
+            ));
+        }
+        function.verify(compiler)?;
+    }
+    Ok(())
+}
+
+struct Function<'a> {
+    name: &'a str,
+    instructions: &'a [Instruction],
+    register_count: u32,
+    upvalue_count: usize,
+    is_main: bool,
+}
+
+impl Function<'_> {
+    fn error(&self, pc: usize, msg: String) -> Error {
+        let name = if self.name.is_empty() {
+            "<anonymous>"
+        } else {
+            self.name
+        };
+        Error::Bytecode(format!(
+            "Bytecode verification failed in {} at instruction {}: {}",
+            name, pc, msg
+        ))
+    }
+
+    fn verify(&self, compiler: &Compiler) -> Result<(), Error> {
+        for (pc, inst) in self.instructions.iter().enumerate() {
+            for reg in registers(inst) {
+                if reg >= self.register_count as usize {
+                    return Err(self.error(
+                        pc,
+                        format!(
+                            "register {} out of range, function has {} registers",
+                            reg, self.register_count
+                        ),
+                    ));
+                }
+            }
+            self.verify_operands(compiler, pc, inst)?;
+        }
+        Ok(())
+    }
+
+    fn verify_operands(&self, compiler: &Compiler, pc: usize, inst: &Instruction) -> Result<(), Error> {
+        match inst.opcode {
+            OpCode::LoadK | OpCode::GetGlobal | OpCode::SetGlobal | OpCode::GetBuiltin
+                if inst.bx() as usize >= compiler.constants.len() =>
+            {
+                return Err(self.error(pc, format!("constant {} out of range", inst.bx())));
+            }
+            OpCode::Closure => match compiler.prototypes.get(inst.bx() as usize) {
+                Some(prototype) => {
+                    for upvalue in &prototype.upvalues {
+                        self.verify_capture(pc, upvalue)?;
+                    }
+                }
+                None => {
+                    return Err(self.error(pc, format!("prototype {} out of range", inst.bx())));
+                }
+            },
+            OpCode::Wide => {
+                return Err(self.error(pc, "wide instruction in compiled code".to_string()));
+            }
+            OpCode::Jmp => {
+                self.verify_target(pc, pc as i64 + inst.sbx() as i64)?;
+            }
+            OpCode::RegisterTryCatch => {
+                self.verify_target(pc, pc as i64 + inst.bx() as i64)?;
+            }
+            OpCode::GetUpval | OpCode::SetUpval if inst.b as usize >= self.upvalue_count => {
+                return Err(self.error(pc, format!("upvalue {} out of range", inst.b)));
+            }
+            OpCode::GetCurrentFunc if self.is_main => {
+                return Err(self.error(pc, "no current function at the top level".to_string()));
+            }
+            OpCode::This | OpCode::Super if self.is_main => {
+                return Err(self.error(pc, "no object bound at the top level".to_string()));
+            }
+            // The Class call stores the instance in R(C-1) whatever C is
+            OpCode::Call if inst.c == 0 => {
+                return Err(self.error(pc, "call without a result register".to_string()));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Jumping right past the last instruction ends the function
+    fn verify_target(&self, pc: usize, target: i64) -> Result<(), Error> {
+        if target < 0 || target > self.instructions.len() as i64 {
+            return Err(self.error(pc, format!("jump target {} out of range", target)));
+        }
+        Ok(())
+    }
+
+    // Closures capture registers or upvalues of the function creating them
+    fn verify_capture(&self, pc: usize, upvalue: &UpvalueRef) -> Result<(), Error> {
+        let valid = if upvalue.is_local {
+            upvalue.index < self.register_count
+        } else {
+            (upvalue.index as usize) < self.upvalue_count
+        };
+        if !valid {
+            return Err(self.error(
+                pc,
+                format!("closure captures invalid upvalue {}", upvalue.index),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Registers read or written by the instruction
+fn registers(inst: &Instruction) -> Vec<usize> {
+    let (a, b, c) = (inst.a as usize, inst.b as usize, inst.c as usize);
+    match inst.opcode {
+        OpCode::Jmp | OpCode::RegisterTryCatch | OpCode::DeregisterTryCatch | OpCode::Wide => vec![],
+        OpCode::LoadK
+        | OpCode::LoadNil
+        | OpCode::Closure
+        | OpCode::List
+        | OpCode::Dict
+        | OpCode::Slice
+        | OpCode::This
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetCurrentFunc
+        | OpCode::GetBuiltin
+        | OpCode::GetExcept
+        | OpCode::Throw
+        | OpCode::GetUpval
+        | OpCode::SetUpval => vec![a],
+        OpCode::Move
+        | OpCode::Test
+        | OpCode::Not
+        | OpCode::Neg
+        | OpCode::PushList
+        | OpCode::Addi
+        | OpCode::Subi
+        | OpCode::GetIteri
+        | OpCode::Length
+        | OpCode::Super => vec![a, b],
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Div
+        | OpCode::Mod
+        | OpCode::Mul
+        | OpCode::Pow
+        | OpCode::Lt
+        | OpCode::Gt
+        | OpCode::Lte
+        | OpCode::Gte
+        | OpCode::Eq
+        | OpCode::Neq
+        | OpCode::PushDict
+        | OpCode::Access
+        | OpCode::Set
+        | OpCode::Class
+        | OpCode::ClassMeth
+        | OpCode::ClassStMeth
+        | OpCode::GetObj
+        | OpCode::SetObj
+        | OpCode::GetIter
+        | OpCode::GetIterk => vec![a, b, c],
+        // R(A) is the function, arguments follow it, the result goes to R(C-1)
+        OpCode::Call => {
+            let mut regs = vec![a];
+            if b > 1 {
+                regs.push(a + b - 1);
+            }
+            if c > 0 {
+                regs.push(c - 1);
+            }
+            regs
+        }
+        // R(A) is the result of joining the C registers starting at R(B)
+        OpCode::Concat => {
+            let mut regs = vec![a];
+            if c > 0 {
+                regs.push(b);
+                regs.push(b + c - 1);
+            }
+            regs
+        }
+        // R(A) only holds a value when B = A + 2
+        OpCode::Return => {
+            if b == a + 2 {
+                vec![a]
+            } else {
+                vec![]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Runtime;
+
+    fn message(result: Result<(), Error>) -> String {
+        match result {
+            Err(Error::Bytecode(msg)) => msg,
+            _ => panic!("expected a verification error"),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut runtime = Runtime::new("test.gr".to_string());
+        let source = "let a = 1\nfn f(x) {\n    return fn() x + a\n}\nwhile a < 3 {\n    a = f(a)()\n}";
+        let compiler = runtime.compile(source.to_string()).unwrap().clone();
+        assert!(verify(&compiler).is_ok());
+
+        let mut bad = compiler.clone();
+        bad.prototypes[0].instructions[1].a = 200;
+        assert!(message(verify(&bad)).contains("register 200 out of range"));
+
+        let mut bad = compiler.clone();
+        let main = &mut bad.contexts[0].chunks[0].instructions[0].inst;
+        main.b = 0xff;
+        assert!(message(verify(&bad)).contains("constant"));
+
+        let mut bad = compiler.clone();
+        bad.prototypes[0].upvalues.push(UpvalueRef {
+            is_local: false,
+            index: 5,
+        });
+        assert!(message(verify(&bad)).contains("invalid upvalue 5"));
+
+        let mut bad = compiler.clone();
+        bad.prototypes[0].param_count = 300;
+        assert!(message(verify(&bad)).contains("300 parameters"));
+
+        // The function takes R(0), there is no room for a parameter in a single register
+        let mut bad = compiler.clone();
+        bad.prototypes[0].param_count = 1;
+        bad.prototypes[0].register_count = 1;
+        assert!(message(verify(&bad)).contains("1 parameters don't fit in 1 registers"));
+
+        let mut bad = compiler.clone();
+        let main = &mut bad.contexts[0].chunks[0].instructions[0].inst;
+        main.opcode = OpCode::This;
+        assert!(message(verify(&bad)).contains("no object bound"));
+
+        let mut bad = compiler.clone();
+        let call = bad.contexts[0]
+            .chunks
+            .iter_mut()
+            .flat_map(|c| c.instructions.iter_mut())
+            .find(|i| i.inst.opcode == OpCode::Call)
+            .unwrap();
+        call.inst.c = 0;
+        assert!(message(verify(&bad)).contains("without a result register"));
+
+        let mut bad = compiler.clone();
+        let jmp = bad.contexts[0]
+            .chunks
+            .iter_mut()
+            .flat_map(|c| c.instructions.iter_mut())
+            .find(|i| i.inst.opcode == OpCode::Jmp)
+            .unwrap();
+        jmp.inst.set_sbx(0x7f00);
+        assert!(message(verify(&bad)).contains("jump target"));
+    }
+}
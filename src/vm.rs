@@ -1,12 +1,11 @@
 #![allow(dead_code)]
 
-use crate::compiler::UpvalueRef;
+use crate::compiler::{Compiler, UpvalueRef};
 use crate::errors::*;
 use crate::instruction::*;
 use crate::token::TokenData;
 use crate::value::*;
 use std::collections::HashMap;
-use std::env;
 use std::ops::Deref;
 use std::rc::Rc;
 
@@ -44,6 +43,9 @@ macro_rules! make_call {
             caller_this: $current_this.clone(),
             current_this: $bind_to.clone(),
             file: None,
+            source: None,
+            call_pc: Some($pc),
+            instructions_data: None,
         };
         let previous_sp = $sp;
         $sp = $self.activation_records.len();
@@ -74,22 +76,16 @@ macro_rules! make_call {
 
 macro_rules! throw_exception {
     ( $self:expr, $this:expr, $original_instructions:expr, $original_instructions_data:expr, $pc:expr, $sp:expr, $error:expr ) => {{
+        let error = $self.raise($error, $pc);
         if let Some(catch_exc) = $self.catch_exceptions.last_mut() {
-            // Pop all frames
-            for i in (($self.frames.len() - 1)..catch_exc.stack_ix) {
-                if let Some(fn_value) = &$self.frames[i].function {
-                    let prototype = &$self.prototypes[fn_value.0.borrow().prototype as usize];
-                    let new_length =
-                        $self.activation_records.len() - prototype.register_count as usize;
-                    $self.activation_records.truncate(new_length);
-                }
-                $self.frames.pop();
-            }
+            // Pop all frames above the one that registered the handler
+            $self.frames.truncate(catch_exc.stack_ix + 1);
+            $self.activation_records.truncate(catch_exc.records_len);
             let stack = &$self.frames[catch_exc.stack_ix];
             $this = stack.current_this.clone();
             $sp = catch_exc.sp;
             $pc = catch_exc.catch_block_pc;
-            catch_exc.exception = Some($error);
+            catch_exc.exception = Some(error);
 
             if let Some(func) = &stack.function {
                 let proto = &$self.prototypes[func.0.borrow().prototype as usize];
@@ -101,20 +97,9 @@ macro_rules! throw_exception {
             }
             continue;
         } else {
-            let skip_backtrace = env::var("GROTSKY_SKIP_BACKTRACE").unwrap_or("0".to_string());
-            if skip_backtrace != "1" && !skip_backtrace.eq_ignore_ascii_case("true") {
-                for stack in $self.frames.iter() {
-                    if let Some(fn_value) = &stack.function {
-                        let prototype = &$self.prototypes[fn_value.0.borrow().prototype as usize];
-                        println!("{}::{}", prototype.file_path, fn_value.0.borrow().name);
-                    } else {
-                        println!("{}", $self.frames[0].file.as_deref().unwrap_or("<unknown_main_script>"));
-                    }
-                }
-            }
-            $self.exception($error, $self.instructions_data[$pc].clone());
+            let token = $self.instructions_data[$pc].clone();
+            return Err($self.exception(error, token));
         }
-        unreachable!();
     }};
 }
 
@@ -123,10 +108,13 @@ pub struct StackEntry {
     pub function: Option<MutValue<FnValue>>, // Empty when main function
     pub pc: usize,                           // Return location
     pub sp: usize,                           // Stack pointer inside activation record
-    pub result_register: u8,
+    pub result_register: u32,
     pub caller_this: Option<MutValue<ObjectValue>>, // The 'this' of the calling context
     pub current_this: Option<MutValue<ObjectValue>>, // The 'this' bound for the current function call
     pub file: Option<String>,
+    pub source: Option<Rc<str>>, // Source code of the main function, when it is known
+    pub call_pc: Option<usize>, // Location of the call in the calling frame, empty when called from Rust
+    pub instructions_data: Option<Rc<Vec<Option<TokenData>>>>, // Source of the main function
 }
 
 #[derive(Debug, Clone)]
@@ -161,24 +149,26 @@ impl Record {
 pub struct CatchException {
     stack_ix: usize,
     sp: usize,
+    records_len: usize,
     catch_block_pc: usize,
     exception: Option<RuntimeErr>,
 }
 
 #[derive(Debug)]
 pub struct VMFnPrototype {
-    pub instructions: Rc<Vec<Instruction>>,
-    pub register_count: u8,
+    pub instructions: Rc<Code>,
+    pub register_count: u32,
     pub upvalues: Vec<UpvalueRef>,
     pub instruction_data: Rc<Vec<Option<TokenData>>>,
     pub param_count: usize,
     pub name: String,
     pub file_path: String,
+    pub source: Option<Rc<str>>,
 }
 
 #[derive(Debug)]
 pub struct VM {
-    pub instructions: Rc<Vec<Instruction>>,
+    pub instructions: Rc<Code>,
     pub prototypes: Rc<Vec<VMFnPrototype>>,
     pub constants: Vec<Value>,
     pub globals: HashMap<String, Value>,
@@ -187,17 +177,21 @@ pub struct VM {
     pub activation_records: Vec<Record>,
     pub instructions_data: Rc<Vec<Option<TokenData>>>,
     pub catch_exceptions: Vec<CatchException>,
+    // Location of the native function being called, for frames it pushes
+    pub native_call_pc: Option<usize>,
+    // Kept alongside the VM so that modules can be compiled at runtime
+    pub compiler: Compiler,
 }
 
 impl VM {
-    pub fn interpret(&mut self) {
+    pub fn interpret(&mut self) -> Result<Value, Exception> {
         let mut pc = self.frames[self.frames.len() - 1].pc;
         let mut sp = self.frames[self.frames.len() - 1].sp;
-        let mut this: Option<MutValue<ObjectValue>> = None;
+        let mut this: Option<MutValue<ObjectValue>> = self.frames[self.frames.len() - 1].current_this.clone();
         let original_instructions_data = self.instructions_data.clone();
         let original_instructions = self.instructions.clone();
         while pc < self.instructions.len() {
-            let inst = self.instructions[pc].clone();
+            let inst = self.instructions.get(pc);
             match inst.opcode {
                 OpCode::LoadK => {
                     self.activation_records[sp + inst.a as usize] =
@@ -267,7 +261,7 @@ impl VM {
                             );
                         }
                         Value::Native(n) => {
-                            if let Some(callable) = n.callable {
+                            if let Some(callable) = n.callable.clone() {
                                 let mut args: Vec<Value> = vec![];
                                 if n.bind {
                                     args.push(val.clone());
@@ -279,7 +273,9 @@ impl VM {
                                     };
                                     args.push(val);
                                 }
-                                let result = callable(args);
+                                let caller_pc = self.native_call_pc.replace(pc);
+                                let result = callable(self, args);
+                                self.native_call_pc = caller_pc;
                                 match result {
                                     Ok(v) => {
                                         if inst.c > 0 {
@@ -362,15 +358,7 @@ impl VM {
 
                     if self.frames.len() == 0 {
                         // Exit program
-                        let mut exit_code = 0;
-                        if let Some(v) = &return_value {
-                            if let Value::Number(n) = v.as_val() {
-                                if n.n >= 0.0 && n.n < 256.0 && n.n.fract() == 0.0 {
-                                    exit_code = n.n as i32;
-                                }
-                            }
-                        }
-                        std::process::exit(exit_code);
+                        return Ok(return_value.map(|v| v.as_val()).unwrap_or(Value::Nil));
                     }
 
                     if return_value.is_some() && stack.result_register > 0 {
@@ -504,29 +492,85 @@ impl VM {
                 OpCode::Pow => {
                     let rec_b = &self.activation_records[sp + inst.b as usize];
                     let rec_c = &self.activation_records[sp + inst.c as usize];
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(rec_b.with_val(|b| rec_c.with_val(|c| b.pow(c))));
+                    match rec_b.with_val(|b| rec_c.with_val(|c| b.pow(c))) {
+                        Ok(v) => {
+                            self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                        }
+                        Err(e) => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                e
+                            );
+                        }
+                    }
                     pc += 1;
                 }
                 OpCode::Div => {
                     let rec_b = &self.activation_records[sp + inst.b as usize];
                     let rec_c = &self.activation_records[sp + inst.c as usize];
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(rec_b.with_val(|b| rec_c.with_val(|c| b.div(c))));
+                    match rec_b.with_val(|b| rec_c.with_val(|c| b.div(c))) {
+                        Ok(v) => {
+                            self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                        }
+                        Err(e) => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                e
+                            );
+                        }
+                    }
                     pc += 1;
                 }
                 OpCode::Mod => {
                     let rec_b = &self.activation_records[sp + inst.b as usize];
                     let rec_c = &self.activation_records[sp + inst.c as usize];
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(rec_b.with_val(|b| rec_c.with_val(|c| b.modulo(c))));
+                    match rec_b.with_val(|b| rec_c.with_val(|c| b.modulo(c))) {
+                        Ok(v) => {
+                            self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                        }
+                        Err(e) => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                e
+                            );
+                        }
+                    }
                     pc += 1;
                 }
                 OpCode::Lt => {
                     let rec_b = &self.activation_records[sp + inst.b as usize];
                     let rec_c = &self.activation_records[sp + inst.c as usize];
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(rec_b.with_val(|b| rec_c.with_val(|c| b.lt(c))));
+                    match rec_b.with_val(|b| rec_c.with_val(|c| b.lt(c))) {
+                        Ok(v) => {
+                            self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                        }
+                        Err(e) => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                e
+                            );
+                        }
+                    }
                     pc += 1;
                 }
                 OpCode::Lte => {
@@ -553,15 +597,43 @@ impl VM {
                 OpCode::Gt => {
                     let rec_b = &self.activation_records[sp + inst.b as usize];
                     let rec_c = &self.activation_records[sp + inst.c as usize];
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(rec_b.with_val(|b| rec_c.with_val(|c| b.gt(c))));
+                    match rec_b.with_val(|b| rec_c.with_val(|c| b.gt(c))) {
+                        Ok(v) => {
+                            self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                        }
+                        Err(e) => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                e
+                            );
+                        }
+                    }
                     pc += 1;
                 }
                 OpCode::Gte => {
                     let rec_b = &self.activation_records[sp + inst.b as usize];
                     let rec_c = &self.activation_records[sp + inst.c as usize];
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(rec_b.with_val(|b| rec_c.with_val(|c| b.gte(c))));
+                    match rec_b.with_val(|b| rec_c.with_val(|c| b.gte(c))) {
+                        Ok(v) => {
+                            self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                        }
+                        Err(e) => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                e
+                            );
+                        }
+                    }
                     pc += 1;
                 }
                 OpCode::Eq => {
@@ -812,15 +884,32 @@ impl VM {
                                 list.0.borrow_mut().elements[nval.n as usize] = val;
                                 pc += 1;
                             } else {
-                                panic!("Index error");
+                                throw_exception!(
+                                    self,
+                                    this,
+                                    original_instructions,
+                                    original_instructions_data,
+                                    pc,
+                                    sp,
+                                    ERR_ONLY_NUMBERS
+                                );
                             }
                         }
                         Value::Dict(dict) => {
                             dict.0.borrow_mut().elements.insert(accessor, val);
                             pc += 1;
                         }
-                        Value::String(_str) => unimplemented!(),
-                        _ => panic!("Cannot access non iterable"),
+                        _ => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                ERR_INVALID_ACCESS
+                            );
+                        }
                     }
                 }
                 OpCode::Class => {
@@ -1370,7 +1459,21 @@ impl VM {
                             ERR_EXPECTED_STRING
                         );
                     };
-                    let _obj = this.as_ref().unwrap().clone();
+                    // Super used outside a method has no object to bind to
+                    let _obj = match this.as_ref() {
+                        Some(obj) => obj.clone(),
+                        None => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                ERR_UNDEFINED_VAR
+                            );
+                        }
+                    };
                     let o = _obj.0.borrow();
                     let cls = o.class.0.borrow();
                     if let Some(supercls) = &cls.superclass {
@@ -1403,8 +1506,21 @@ impl VM {
                     pc += 1;
                 }
                 OpCode::This => {
-                    self.activation_records[sp + inst.a as usize] =
-                        Record::Val(Value::Object(this.as_ref().unwrap().clone()));
+                    let obj = match this.as_ref() {
+                        Some(obj) => obj.clone(),
+                        None => {
+                            throw_exception!(
+                                self,
+                                this,
+                                original_instructions,
+                                original_instructions_data,
+                                pc,
+                                sp,
+                                ERR_UNDEFINED_VAR
+                            );
+                        }
+                    };
+                    self.activation_records[sp + inst.a as usize] = Record::Val(Value::Object(obj));
                     pc += 1;
                 }
                 OpCode::GetGlobal => {
@@ -1462,8 +1578,24 @@ impl VM {
                 }
                 OpCode::GetBuiltin => {
                     if let Value::String(s) = &self.constants[inst.bx() as usize] {
-                        self.activation_records[sp + inst.a as usize] =
-                            Record::Val(self.builtins.get(&s.0.borrow().s).unwrap().clone());
+                        let builtin = self.builtins.get(&s.0.borrow().s).cloned();
+                        match builtin {
+                            Some(v) => {
+                                self.activation_records[sp + inst.a as usize] = Record::Val(v);
+                            }
+                            None => {
+                                // Bytecode might refer to a builtin this runtime doesn't provide
+                                throw_exception!(
+                                    self,
+                                    this,
+                                    original_instructions,
+                                    original_instructions_data,
+                                    pc,
+                                    sp,
+                                    ERR_UNDEFINED_VAR
+                                );
+                            }
+                        }
                     } else {
                         throw_exception!(
                             self,
@@ -1482,6 +1614,7 @@ impl VM {
                         catch_block_pc: pc + inst.bx() as usize,
                         stack_ix: self.frames.len() - 1,
                         sp: sp,
+                        records_len: self.activation_records.len(),
                         exception: None,
                     });
                     pc += 1;
@@ -1496,7 +1629,12 @@ impl VM {
                     self.activation_records[sp + inst.a as usize] =
                         if let Some(catched_exception) = self.catch_exceptions.last() {
                             if let Some(exc) = &catched_exception.exception {
-                                Record::Val(Value::String(MutValue::new(StringValue::new(exc.msg.to_string()))))
+                                // R(B) = 1 keeps the error as is, so it can be raised again
+                                if inst.b == 1 {
+                                    Record::Val(exc.to_object())
+                                } else {
+                                    Record::Val(exc.to_value())
+                                }
                             } else {
                                 Record::Val(Value::Nil)
                             }
@@ -1505,24 +1643,212 @@ impl VM {
                         };
                     pc += 1;
                 }
+                OpCode::Throw => {
+                    let value = self.activation_records[sp + inst.a as usize].as_val();
+                    throw_exception!(
+                        self,
+                        this,
+                        original_instructions,
+                        original_instructions_data,
+                        pc,
+                        sp,
+                        RuntimeErr::thrown(value)
+                    );
+                }
+                OpCode::Concat => {
+                    let mut s = String::new();
+                    for reg in inst.b..(inst.b + inst.c) {
+                        let rec = &self.activation_records[sp + reg as usize];
+                        rec.with_val(|v| match v {
+                            Value::String(string) => s.push_str(&string.0.borrow().s),
+                            _ => s.push_str(&v.string()),
+                        });
+                    }
+                    self.activation_records[sp + inst.a as usize] =
+                        Record::Val(Value::String(MutValue::new(StringValue::new(s))));
+                    pc += 1;
+                }
+                // Code::get returns the wide instruction in its place
+                OpCode::Wide => unreachable!(),
             }
         }
+        Ok(Value::Nil)
     }
 
-    // pub fn extern_call(&self, prototype: u16, values: Vec<Value>) -> Result<Value, RuntimeErr> {}
+    /// Calls a Grotsky function from Rust code, either from the host or from inside a native function.
+    pub fn extern_call(&mut self, function: Value, values: Vec<Value>) -> Result<Value, Exception> {
+        let fn_value = match function {
+            Value::Fn(fn_value) => fn_value,
+            Value::Native(n) => match n.callable.clone() {
+                Some(callable) => {
+                    let mut args = vec![];
+                    if n.bind {
+                        args.push(Value::Native(n.clone()));
+                    }
+                    args.extend(values);
+                    return callable(self, args).map_err(|e| self.exception(e, None));
+                }
+                None => return Err(self.exception(ERR_ONLY_FUNCTION, None)),
+            },
+            _ => return Err(self.exception(ERR_ONLY_FUNCTION, None)),
+        };
+        let prototype = &self.prototypes[fn_value.0.borrow().prototype as usize];
+        if values.len() != prototype.param_count {
+            return Err(self.exception(ERR_INVALID_NUMBER_ARGUMENTS, None));
+        }
+        let register_count = prototype.register_count as usize;
+        let instructions = prototype.instructions.clone();
+        let instructions_data = prototype.instruction_data.clone();
+
+        // Run the function on its own, as if it was the entrypoint of the program
+        let saved_frames = std::mem::take(&mut self.frames);
+        let saved_catch_exceptions = std::mem::take(&mut self.catch_exceptions);
+        let saved_instructions = std::mem::replace(&mut self.instructions, instructions);
+        let saved_instructions_data =
+            std::mem::replace(&mut self.instructions_data, instructions_data);
+        let sp = self.activation_records.len();
+        self.frames.push(StackEntry {
+            function: Some(fn_value.clone()),
+            pc: 0,
+            sp,
+            result_register: 0,
+            caller_this: None,
+            current_this: fn_value.0.borrow().this.clone(),
+            file: None,
+            source: None,
+            call_pc: self.native_call_pc,
+            instructions_data: None,
+        });
+        self.activation_records
+            .resize(sp + register_count, Record::Val(Value::Nil));
+        for (i, value) in values.into_iter().enumerate() {
+            self.activation_records[sp + i + 1] = Record::Val(value);
+        }
+
+        let result = self.interpret();
 
-    pub fn exception(&self, error: RuntimeErr, token: Option<TokenData>) {
-        match token {
-            Some(tk) => {
-                print!(
-                    "Runtime Error on line {}\n\t{}: {}\n",
-                    tk.line, error.msg, tk.lexeme
-                );
-            }
-            None => {
-                print!("Runtime Error\n\t{}\n", error.msg);
+        self.activation_records.truncate(sp);
+        self.frames = saved_frames;
+        self.catch_exceptions = saved_catch_exceptions;
+        self.instructions = saved_instructions;
+        self.instructions_data = saved_instructions_data;
+        result
+    }
+
+    fn frame_file(&self, stack: &StackEntry) -> String {
+        match &stack.function {
+            Some(fn_value) => self.prototypes[fn_value.0.borrow().prototype as usize]
+                .file_path
+                .clone(),
+            None => stack
+                .file
+                .as_deref()
+                .unwrap_or("<unknown_main_script>")
+                .to_string(),
+        }
+    }
+
+    fn frame_source(&self, stack: &StackEntry) -> Option<Rc<str>> {
+        match &stack.function {
+            Some(fn_value) => self.prototypes[fn_value.0.borrow().prototype as usize]
+                .source
+                .clone(),
+            None => stack.source.clone(),
+        }
+    }
+
+    fn frame_line(&self, stack: &StackEntry, pc: usize) -> Option<i32> {
+        let instructions_data = match &stack.function {
+            Some(fn_value) => &self.prototypes[fn_value.0.borrow().prototype as usize].instruction_data,
+            None => stack.instructions_data.as_ref()?,
+        };
+        instructions_data.get(pc)?.as_ref().map(|tk| tk.line)
+    }
+
+    // Each frame is at the call of the next one, the last one at pc
+    fn stack_trace(&self, pc: Option<usize>) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .enumerate()
+            .map(|(i, stack)| {
+                let frame_pc = match self.frames.get(i + 1) {
+                    Some(next) => next.call_pc,
+                    None => pc,
+                };
+                TraceFrame {
+                    file: self.frame_file(stack),
+                    function: match &stack.function {
+                        Some(fn_value) if !fn_value.0.borrow().name.is_empty() => {
+                            fn_value.0.borrow().name.clone()
+                        }
+                        Some(_) => "<anonymous>".to_string(),
+                        None => "<main>".to_string(),
+                    },
+                    line: frame_pc.and_then(|pc| self.frame_line(stack, pc)),
+                }
+            })
+            .collect()
+    }
+
+    // Records where the error was raised, unless it comes from somewhere else already
+    fn raise(&self, mut error: RuntimeErr, pc: usize) -> RuntimeErr {
+        if error.location.is_none() {
+            if let Some(Some(tk)) = self.instructions_data.get(pc) {
+                let frame = self.frames.last().unwrap();
+                error.location = Some(Box::new(Location {
+                    file: self.frame_file(frame),
+                    line: tk.line,
+                    column: tk.column,
+                    source_line: self.frame_source(frame).and_then(|s| source_line(&s, tk.line)),
+                }));
+                error.token = Some(Box::new(tk.clone()));
             }
         }
-        std::process::exit(0);
+        if error.stack.is_empty() {
+            error.stack = self.stack_trace(Some(pc));
+        }
+        error
+    }
+
+    pub fn exception(&self, mut error: RuntimeErr, token: Option<TokenData>) -> Exception {
+        if error.stack.is_empty() {
+            error.stack = self.stack_trace(None);
+        }
+        // Errors raised again keep pointing to their origin
+        if error.token.is_none() {
+            error.token = token.map(Box::new);
+        }
+        Exception { err: error }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Runtime;
+
+    #[test]
+    fn test_extern_call() {
+        let mut runtime = Runtime::new("test.gr".to_string());
+        // Natives can call back into the script
+        runtime.register_function("apply", |vm, mut values| {
+            let function = values.remove(0);
+            vm.extern_call(function, values).map_err(|exc| exc.err)
+        });
+        let source = r#"
+fn add(a, b) a + b
+fn fail(f, args) {
+    try {
+        apply(f, args)
+    } catch err {
+        return err.message
+    }
+}
+return [apply(add, 1, 2), apply(strings.toUpper, "a"), fail(1, nil), fail(add, 1)]
+"#;
+        let result = runtime.run_bytecode_interpreter(source.to_string()).unwrap();
+        assert_eq!(
+            result.string(),
+            "[3, \"A\", \"Can only call functions\", \"Invalid number of arguments\"]"
+        );
     }
 }
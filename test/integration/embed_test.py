@@ -79,5 +79,48 @@ class TestEmbed(unittest.TestCase):
                             "This is expected for unsigned executables on macOS.")
             raise
 
+    def test_embed_assets(self):
+        script_path = os.path.join(self.temp_dir, "site.gr")
+        with open(script_path, "w") as f:
+            f.write('io.println(assets.list())\n'
+                    'io.println(assets.read("templates/index.html"))\n'
+                    'io.println(assets.exists("missing.txt"))')
+        os.makedirs(os.path.join(self.temp_dir, "templates"))
+        with open(os.path.join(self.temp_dir, "templates", "index.html"), "w") as f:
+            f.write("<h1>Hello</h1>")
+
+        subprocess.check_call([self.grotsky_bin, "compile", script_path])
+        subprocess.check_call([self.grotsky_bin, "embed", "-o", "dist/site", "site.grc", "templates"],
+                              cwd=self.temp_dir)
+        # The executable doesn't need the files anymore
+        shutil.rmtree(os.path.join(self.temp_dir, "templates"))
+
+        exe_path = os.path.join(self.temp_dir, "dist", "site")
+        self.assertTrue(os.access(exe_path, os.X_OK))
+        result = subprocess.run([exe_path], capture_output=True, text=True, timeout=5)
+        if platform.system() == "Darwin" and result.returncode == -9:
+            self.skipTest("Embedded executable blocked by macOS Gatekeeper (SIGKILL).")
+        self.assertEqual(result.returncode, 0, result.stderr)
+        self.assertEqual(result.stdout, '["templates/index.html"]\n<h1>Hello</h1>\nfalse\n')
+
+    def test_info_and_extract(self):
+        script_path = os.path.join(self.temp_dir, "app.gr")
+        with open(script_path, "w") as f:
+            f.write('io.println("app")')
+        subprocess.check_call([self.grotsky_bin, "compile", script_path])
+        exe_path = os.path.join(self.temp_dir, "app")
+        subprocess.check_call([self.grotsky_bin, "embed", "-o", exe_path, script_path + "c"])
+
+        info = subprocess.run([self.grotsky_bin, "embed", "--info", exe_path],
+                              capture_output=True, text=True, check=True).stdout
+        self.assertIn("payload:", info)
+        self.assertIn("format", info)
+        self.assertIn("assets (0):", info)
+
+        extracted = os.path.join(self.temp_dir, "extracted.grc")
+        subprocess.check_call([self.grotsky_bin, "embed", "--extract", "-o", extracted, exe_path])
+        with open(extracted, "rb") as a, open(script_path + "c", "rb") as b:
+            self.assertEqual(a.read(), b.read())
+
 if __name__ == "__main__":
     unittest.main()